@@ -17,14 +17,62 @@ use std::fs::{read, File};
 use std::io::Write;
 use std::path::PathBuf;
 
+mod usb_id;
+
 fn main() {
-    // Set or overwrite the linker script if needed.
+    memory_x();
+    usb();
+}
+
+/// Sets or overwrites the linker script if needed.
+fn memory_x() {
+    println!("cargo:rerun-if-env-changed=ONEKIBU_MEMORY_X");
     let input = match env::var_os("ONEKIBU_MEMORY_X") {
         None => return,
         Some(x) => x,
     };
+    println!("cargo:rerun-if-changed={}", input.to_str().unwrap());
     let input = read(input).unwrap();
-    let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let out = &out_dir();
     File::create(out.join("memory.x")).unwrap().write_all(&input).unwrap();
     println!("cargo:rustc-link-search={}", out.display());
 }
+
+/// Generates the USB identification of the device.
+///
+/// The defaults depend on the board and can be overwritten with the `ONEKIBU_USB_VID`,
+/// `ONEKIBU_USB_PID` (hexadecimal), `ONEKIBU_USB_MANUFACTURER`, and `ONEKIBU_USB_PRODUCT`
+/// environment variables.
+fn usb() {
+    let board = BOARDS.iter().find(|(feature, _)| env::var_os(feature).is_some());
+    let product = board.map_or("onekibu", |&(_, product)| product);
+    for (name, _) in usb_id::IDS {
+        println!("cargo:rerun-if-env-changed={name}");
+    }
+    let [vid, pid] = usb_id::ids();
+    let manufacturer = var("ONEKIBU_USB_MANUFACTURER", "onekibu");
+    let product = var("ONEKIBU_USB_PRODUCT", product);
+    let mut output = File::create(out_dir().join("usb.rs")).unwrap();
+    writeln!(output, "pub const VID: u16 = {vid:#06x};").unwrap();
+    writeln!(output, "pub const PID: u16 = {pid:#06x};").unwrap();
+    writeln!(output, "pub const MANUFACTURER: &str = {manufacturer:?};").unwrap();
+    writeln!(output, "pub const PRODUCT: &str = {product:?};").unwrap();
+}
+
+/// Default product name of each board feature.
+const BOARDS: &[(&str, &str)] = &[
+    ("CARGO_FEATURE_BOARD_NRF52840_DK", "onekibu (nRF52840 DK)"),
+    ("CARGO_FEATURE_BOARD_NRF52840_DONGLE", "onekibu (nRF52840 dongle)"),
+    ("CARGO_FEATURE_BOARD_NRF52840_MDK_DONGLE", "onekibu (nRF52840 MDK dongle)"),
+    ("CARGO_FEATURE_BOARD_SOLO", "onekibu (Solo)"),
+];
+
+/// Returns an environment variable or its default.
+fn var(name: &str, default: &str) -> String {
+    println!("cargo:rerun-if-env-changed={name}");
+    env::var(name).unwrap_or_else(|_| default.to_string())
+}
+
+fn out_dir() -> PathBuf {
+    PathBuf::from(env::var_os("OUT_DIR").unwrap())
+}
@@ -1,10 +1,14 @@
 __stack_size = 0x10000;
+/* The end of the flash stores the settings (see onekibu::store). */
+__settings_size = 0x4000;
 
 MEMORY
 {
-  FLASH : ORIGIN = 0x00000000, LENGTH = 0x00100000
+  FLASH : ORIGIN = 0x00000000, LENGTH = 0x00100000 - __settings_size
   RAM   : ORIGIN = 0x20000000 + __stack_size, LENGTH = 0x00040000 - __stack_size
 }
 
 _stack_start = ORIGIN(RAM);
 __eheap = ORIGIN(RAM) + LENGTH(RAM);
+__settings_start = ORIGIN(FLASH) + LENGTH(FLASH);
+__settings_end = __settings_start + __settings_size;
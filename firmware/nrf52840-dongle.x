@@ -1,13 +1,17 @@
 __stack_size = 0x10000;
+/* The end of the flash stores the settings (see onekibu::store). */
+__settings_size = 0x4000;
 
 MEMORY
 {
   /* Apparently the bootloader takes the first and last page of the flash.
      See https://github.com/ferrous-systems/embedded-trainings-2020/blob/main/boards/dongle/memory.x
    */
-  FLASH : ORIGIN = 0x00001000, LENGTH = 0x00100000 - 0x2000
+  FLASH : ORIGIN = 0x00001000, LENGTH = 0x00100000 - 0x2000 - __settings_size
   RAM   : ORIGIN = 0x20000000 + __stack_size, LENGTH = 0x00040000 - __stack_size
 }
 
 _stack_start = ORIGIN(RAM);
 __eheap = ORIGIN(RAM) + LENGTH(RAM);
+__settings_start = ORIGIN(FLASH) + LENGTH(FLASH);
+__settings_end = __settings_start + __settings_size;
@@ -0,0 +1,119 @@
+// Copyright 2021-2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use defmt::Format;
+
+use crate::time::Duration;
+
+/// Bounds of the adaptive long press duration.
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Adaptive {
+    pub minimum: Duration,
+    pub maximum: Duration,
+}
+
+/// Number of recent press durations used to learn the long press duration.
+const HISTORY: usize = 5;
+
+/// Recent press durations (most recent ones overwrite the oldest ones).
+struct History {
+    samples: [usize; HISTORY],
+    len: usize,
+    next: usize,
+}
+
+impl History {
+    fn new() -> History {
+        History { samples: [0; HISTORY], len: 0, next: 0 }
+    }
+
+    fn push(&mut self, sample: usize) {
+        self.samples[self.next] = sample;
+        self.next = (self.next + 1) % HISTORY;
+        self.len = core::cmp::min(self.len + 1, HISTORY);
+    }
+
+    fn median(&self) -> Option<usize> {
+        if self.len == 0 {
+            return None;
+        }
+        let mut samples = self.samples;
+        let samples = &mut samples[.. self.len];
+        samples.sort_unstable();
+        Some(samples[self.len / 2])
+    }
+}
+
+/// Learns the long press duration from the user's short and long press durations.
+pub struct Learner {
+    /// Bounds of the long press duration (in ticks).
+    minimum: usize,
+    maximum: usize,
+    shorts: History,
+    longs: History,
+}
+
+impl Learner {
+    pub fn new(minimum: usize, maximum: usize) -> Learner {
+        Learner { minimum, maximum, shorts: History::new(), longs: History::new() }
+    }
+
+    /// Records a press duration and returns the new long press duration.
+    ///
+    /// The long press duration is halfway between the short and long durations, assuming a long
+    /// press is 3 times a short press when one of them is not known yet.
+    pub fn record(&mut self, duration: usize, long: bool) -> usize {
+        match long {
+            false => self.shorts.push(duration),
+            true => self.longs.push(duration),
+        }
+        let long = match (self.shorts.median(), self.longs.median()) {
+            (Some(short), Some(long)) => (short + long) / 2,
+            (Some(short), None) => 2 * short,
+            (None, Some(long)) => 2 * long / 3,
+            (None, None) => unreachable!(),
+        };
+        long.clamp(self.minimum, self.maximum)
+    }
+}
+
+#[test]
+fn median() {
+    let mut history = History::new();
+    assert_eq!(history.median(), None);
+    history.push(30);
+    assert_eq!(history.median(), Some(30));
+    for x in [10, 50, 20, 40] {
+        history.push(x);
+    }
+    assert_eq!(history.median(), Some(30));
+    // The oldest sample (30) is overwritten.
+    history.push(60);
+    assert_eq!(history.median(), Some(40));
+}
+
+#[test]
+fn record() {
+    let mut learner = Learner::new(50, 200);
+    assert_eq!(learner.record(40, false), 80);
+    assert_eq!(learner.record(150, true), 95);
+    assert_eq!(learner.record(20, false), 95);
+    for _ in 0 .. 4 {
+        assert_eq!(learner.record(20, false), 85);
+    }
+    assert_eq!(learner.record(60, true), 85);
+    assert_eq!(learner.record(60, true), 50);
+    let mut learner = Learner::new(50, 200);
+    assert_eq!(learner.record(600, true), 200);
+}
@@ -0,0 +1,104 @@
+// Copyright 2021-2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Translates ASCII to keycodes assuming a US layout on the host.
+
+use alloc::vec::Vec;
+
+use crate::keymap::Error;
+use crate::Action;
+
+/// Left shift keycode.
+const SHIFT: u8 = 225;
+
+/// Returns the keycode of an ASCII character and whether shift is needed.
+pub fn translate(x: u8) -> Option<(bool, u8)> {
+    let key = match x {
+        b'a' ..= b'z' => return Some((false, x - b'a' + 4)),
+        b'A' ..= b'Z' => return Some((true, x - b'A' + 4)),
+        b'1' ..= b'9' => return Some((false, x - b'1' + 30)),
+        b'0' => (false, 39),
+        b'\n' => (false, 40),
+        0x1b => (false, 41),
+        0x08 => (false, 42),
+        b'\t' => (false, 43),
+        b' ' => (false, 44),
+        b'!' => (true, 30),
+        b'@' => (true, 31),
+        b'#' => (true, 32),
+        b'$' => (true, 33),
+        b'%' => (true, 34),
+        b'^' => (true, 35),
+        b'&' => (true, 36),
+        b'*' => (true, 37),
+        b'(' => (true, 38),
+        b')' => (true, 39),
+        b'-' => (false, 45),
+        b'_' => (true, 45),
+        b'=' => (false, 46),
+        b'+' => (true, 46),
+        b'[' => (false, 47),
+        b'{' => (true, 47),
+        b']' => (false, 48),
+        b'}' => (true, 48),
+        b'\\' => (false, 49),
+        b'|' => (true, 49),
+        b';' => (false, 51),
+        b':' => (true, 51),
+        b'\'' => (false, 52),
+        b'"' => (true, 52),
+        b'`' => (false, 53),
+        b'~' => (true, 53),
+        b',' => (false, 54),
+        b'<' => (true, 54),
+        b'.' => (false, 55),
+        b'>' => (true, 55),
+        b'/' => (false, 56),
+        b'?' => (true, 56),
+        _ => return None,
+    };
+    Some(key)
+}
+
+/// Returns the actions typing an ASCII text.
+pub fn text(text: &str) -> Result<Vec<Action>, Error> {
+    let mut actions = Vec::new();
+    for x in text.bytes() {
+        let (shift, key) = translate(x).ok_or(Error::InvalidCharacter)?;
+        if shift {
+            actions.push(Action::Tap(SHIFT));
+        }
+        actions.push(Action::Tap(key));
+    }
+    Ok(actions)
+}
+
+#[test]
+fn translate_all() {
+    for x in 0x20 .. 0x7f {
+        assert!(translate(x).is_some(), "{:?}", x as char);
+    }
+    assert_eq!(translate(b'k'), Some((false, 14)));
+    assert_eq!(translate(b'K'), Some((true, 14)));
+    assert_eq!(translate(b'7'), Some((false, 36)));
+    assert_eq!(translate(b'&'), Some((true, 36)));
+    assert_eq!(translate(0x7f), None);
+}
+
+#[test]
+fn text_shift() {
+    use Action::Tap;
+    assert_eq!(text("Hi!"), Ok([Tap(SHIFT), Tap(11), Tap(12), Tap(SHIFT), Tap(30)].to_vec()));
+    assert_eq!(text("é"), Err(Error::InvalidCharacter));
+}
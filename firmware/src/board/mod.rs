@@ -15,19 +15,61 @@
 #[cfg(feature = "chip-nrf52840")]
 mod nrf52840;
 #[cfg(feature = "chip-nrf52840")]
-pub use nrf52840::{pac, Board};
+pub use nrf52840::{pac, Board, Mono};
 
 #[cfg(feature = "board-solo")]
 mod solo;
 #[cfg(feature = "board-solo")]
-pub use solo::{pac, Board};
+pub use solo::{pac, Board, Mono};
 
 pub trait BoardApi {
     type UsbBus: usb_device::bus::UsbBus;
+    type Flash: onekibu::store::Flash;
+    /// Whether the board has a bootloader that can be entered from the firmware.
+    const BOOTLOADER: bool;
 
-    fn new(c: rtic::export::Peripherals, p: pac::Peripherals) -> Self;
+    /// Initializes the board and the monotonic timer used to schedule tasks.
+    ///
+    /// The button interrupt is enabled on both edges.
+    fn new(c: rtic::export::Peripherals, p: pac::Peripherals) -> (Self, Mono)
+    where
+        Self: Sized;
     fn usb_bus(&self) -> &'static usb_device::class_prelude::UsbBusAllocator<Self::UsbBus>;
     fn config(&self) -> onekibu::Config;
+    /// Returns the current timestamp (the timer keeps running while sleeping).
+    fn now(&self) -> onekibu::Instant;
     fn input(&self) -> onekibu::Input;
-    fn state(&mut self, state: onekibu::BitState);
+    /// Acknowledges the button interrupt.
+    fn clear_button(&mut self);
+    /// Signals a remote wakeup to the suspended host.
+    fn usb_wakeup(&mut self);
+    /// Ends the remote wakeup signal (called after `RESUME` milliseconds).
+    fn usb_wakeup_end(&mut self);
+    fn state(&mut self, state: onekibu::BitState, modifiers: u8);
+    /// Shows the keyboard LEDs set by the host (e.g. Caps Lock), if possible.
+    fn host_leds(&mut self, leds: onekibu::HostLeds);
+    /// Resets the board into its bootloader (only called if [`Self::BOOTLOADER`]).
+    fn reboot_bootloader(&mut self) -> !;
+    /// Returns the unique identifier of the chip (used as USB serial number).
+    fn device_id(&self) -> &'static [u8];
+    /// Returns the flash region storing the settings (must be called once).
+    fn flash(&mut self) -> Self::Flash;
+}
+
+/// Returns the address range reserved for the settings by the linker script.
+fn settings_region() -> core::ops::Range<usize> {
+    extern "C" {
+        static __settings_start: u32;
+        static __settings_end: u32;
+    }
+    let start = unsafe { &__settings_start } as *const u32 as usize;
+    let end = unsafe { &__settings_end } as *const u32 as usize;
+    assert!(start < end);
+    start .. end
+}
+
+/// Reads memory-mapped flash.
+fn read_flash(address: usize, data: &mut [u8]) {
+    // Unsafe: The settings region is mapped and only modified through exclusive references.
+    unsafe { core::ptr::copy_nonoverlapping(address as *const u8, data.as_mut_ptr(), data.len()) }
 }
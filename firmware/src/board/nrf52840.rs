@@ -12,14 +12,29 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use nrf52840_hal::clocks::{Clocks, ExternalOscillator, Internal, LfOscStopped};
+use nrf52840_hal::clocks::{Clocks, ExternalOscillator, Internal, LfOscStarted};
 use nrf52840_hal::gpio::{self, Input, Level, Output, Pin, PullUp, PushPull};
+use nrf52840_hal::gpiote::Gpiote;
 use nrf52840_hal::prelude::{InputPin, OutputPin};
 use nrf52840_hal::usbd::{UsbPeripheral, Usbd};
+use rtic::Monotonic;
 use usb_device::class_prelude::UsbBusAllocator;
 
 pub use nrf52840_hal::pac;
 
+/// Settings region of the flash, written with the NVMC.
+pub struct Flash {
+    start: usize,
+    pages: usize,
+}
+
+/// Monotonic timer on RTC1, which only interrupts when a task is due.
+pub struct Mono {
+    rtc: pac::RTC1,
+    /// Number of counter overflows (see [`Mono::now()`]).
+    overflows: u64,
+}
+
 pub struct Board {
     #[cfg(feature = "board-nrf52840-dk")]
     button: [Pin<Input<PullUp>>; 4],
@@ -30,15 +45,22 @@ pub struct Board {
     #[cfg(feature = "board-nrf52840-mdk-dongle")]
     leds: [Pin<Output<PushPull>>; 3],
     timer: pac::TIMER0,
+    gpiote: Gpiote,
+    /// Whether modifiers are active.
+    modifiers: bool,
+    /// Whether Caps Lock is on.
+    caps_lock: bool,
 }
 
-static mut CLOCKS: Option<Clocks<ExternalOscillator, Internal, LfOscStopped>> = None;
+static mut CLOCKS: Option<Clocks<ExternalOscillator, Internal, LfOscStarted>> = None;
 static mut USB_BUS: Option<UsbBusAllocator<Usbd<UsbPeripheral<'static>>>> = None;
 
 impl super::BoardApi for Board {
     type UsbBus = Usbd<UsbPeripheral<'static>>;
+    type Flash = Flash;
+    const BOOTLOADER: bool = !cfg!(feature = "board-nrf52840-dk");
 
-    fn new(_c: rtic::export::Peripherals, p: pac::Peripherals) -> Board {
+    fn new(_: rtic::export::Peripherals, p: pac::Peripherals) -> (Board, Mono) {
         let port0 = gpio::p0::Parts::new(p.P0);
         #[cfg(feature = "board-nrf52840-dongle")]
         let port1 = gpio::p1::Parts::new(p.P1);
@@ -73,6 +95,16 @@ impl super::BoardApi for Board {
             port0.p0_22.into_push_pull_output(Level::High).degrade(),
             port0.p0_24.into_push_pull_output(Level::High).degrade(),
         ];
+        let gpiote = Gpiote::new(p.GPIOTE);
+        #[cfg(feature = "board-nrf52840-dk")]
+        {
+            gpiote.channel0().input_pin(&button[0]).toggle().enable_interrupt();
+            gpiote.channel1().input_pin(&button[1]).toggle().enable_interrupt();
+            gpiote.channel2().input_pin(&button[2]).toggle().enable_interrupt();
+            gpiote.channel3().input_pin(&button[3]).toggle().enable_interrupt();
+        }
+        #[cfg(any(feature = "board-nrf52840-dongle", feature = "board-nrf52840-mdk-dongle"))]
+        gpiote.channel0().input_pin(&button).toggle().enable_interrupt();
         let timer = p.TIMER0;
         timer.prescaler.write(
             |w| unsafe { w.prescaler().bits(4) }, // 1 MHz
@@ -80,11 +112,14 @@ impl super::BoardApi for Board {
         timer.bitmode.write(|w| w.bitmode()._32bit());
         timer.tasks_start.write(|w| w.tasks_start().set_bit());
         unsafe {
-            CLOCKS = Some(Clocks::new(p.CLOCK).enable_ext_hfosc());
+            // The low frequency clock drives the RTC.
+            CLOCKS = Some(Clocks::new(p.CLOCK).enable_ext_hfosc().start_lfclk());
             let clocks = CLOCKS.as_ref().unwrap();
             USB_BUS = Some(Usbd::new(UsbPeripheral::new(p.USBD, clocks)));
         }
-        Board { button, leds, timer }
+        let mono = Mono::new(p.RTC1);
+        let board = Board { button, leds, timer, gpiote, modifiers: false, caps_lock: false };
+        (board, mono)
     }
 
     fn usb_bus(&self) -> &'static UsbBusAllocator<Self::UsbBus> {
@@ -92,13 +127,36 @@ impl super::BoardApi for Board {
     }
 
     fn config(&self) -> onekibu::Config {
-        let dit = 80000; // 80ms
-        onekibu::Config { maximum: u32::MAX as usize, period: 2 * dit }
+        let ms = onekibu::Duration::from_millis;
+        let dit = 80;
+        let debounce = onekibu::Debounce::Integrator(ms(5));
+        let modifier = onekibu::ModifierMode::OneShot;
+        onekibu::Config {
+            // TIMER0 runs at 1MHz in 32-bit mode.
+            clock: onekibu::Clock { frequency: 1_000_000, maximum: u32::MAX as usize },
+            long: ms(2 * dit),
+            cancel: ms(4 * dit),
+            letter: ms(2 * dit),
+            word: None,
+            repeat: Some(onekibu::Repeat {
+                window: ms(2 * dit),
+                delay: ms(3 * dit),
+                interval: ms(dit),
+            }),
+            adaptive: None,
+            debounce,
+            lag: onekibu::Lag::Clamp,
+            modifier,
+        }
     }
 
-    fn input(&self) -> onekibu::Input {
+    fn now(&self) -> onekibu::Instant {
         self.timer.tasks_capture[1].write(|w| w.tasks_capture().set_bit());
-        let timestamp = self.timer.cc[1].read().bits() as usize;
+        onekibu::Instant::from_ticks(self.timer.cc[1].read().bits() as usize)
+    }
+
+    fn input(&self) -> onekibu::Input {
+        let timestamp = self.now();
         #[cfg(feature = "board-nrf52840-dk")]
         let button = self.button.iter().any(|x| x.is_low().unwrap());
         #[cfg(any(feature = "board-nrf52840-dongle", feature = "board-nrf52840-mdk-dongle"))]
@@ -106,15 +164,36 @@ impl super::BoardApi for Board {
         onekibu::Input { timestamp, button }
     }
 
-    fn state(&mut self, state: onekibu::BitState) {
+    fn clear_button(&mut self) {
+        self.gpiote.reset_events();
+    }
+
+    fn usb_wakeup(&mut self) {
+        // The USB peripheral is owned by the USB bus, which doesn't support remote wakeup.
+        let usbd = unsafe { &*pac::USBD::ptr() };
+        usbd.lowpower.write(|w| w.lowpower().force_normal());
+        usbd.dpdmvalue.write(|w| w.state().resume());
+        usbd.tasks_dpdmdrive.write(|w| unsafe { w.bits(1) });
+    }
+
+    fn usb_wakeup_end(&mut self) {
+        // The resume signal ends by itself.
+    }
+
+    fn state(&mut self, state: onekibu::BitState, modifiers: u8) {
         // TODO: Use a PWM.
-        let bits = match state {
-            onekibu::BitState::Ready => [0, 0, 0, 0],
+        let mut bits = match state {
+            onekibu::BitState::Ready | onekibu::BitState::Gap => [0, 0, 0, 0],
             onekibu::BitState::Short => [0, 0, 1, 0],
             onekibu::BitState::Long => [0, 1, 1, 0],
+            onekibu::BitState::Repeat => [0, 1, 1, 1],
             onekibu::BitState::Cancel => [0, 1, 0, 0],
             onekibu::BitState::Done => [0, 0, 0, 1],
         };
+        // The first LED shows whether modifiers are active or Caps Lock is on (except on the MDK
+        // dongle).
+        self.modifiers = modifiers != 0;
+        bits[0] = (self.modifiers || self.caps_lock) as u8;
         #[cfg(feature = "board-nrf52840-mdk-dongle")]
         let bits = &bits[1 ..];
         for (i, &b) in bits.iter().enumerate() {
@@ -125,4 +204,140 @@ impl super::BoardApi for Board {
             }
         }
     }
+
+    fn host_leds(&mut self, leds: onekibu::HostLeds) {
+        self.caps_lock = leds.caps_lock;
+        #[cfg(not(feature = "board-nrf52840-mdk-dongle"))]
+        if self.modifiers || self.caps_lock {
+            self.leds[0].set_low().unwrap();
+        } else {
+            self.leds[0].set_high().unwrap();
+        }
+    }
+
+    fn reboot_bootloader(&mut self) -> ! {
+        // The bootloader checks GPREGRET after reset.
+        #[cfg(feature = "board-nrf52840-dongle")]
+        let magic = Some(0xb1); // Nordic open bootloader
+        #[cfg(feature = "board-nrf52840-mdk-dongle")]
+        let magic = Some(0x57); // UF2 bootloader
+        #[cfg(feature = "board-nrf52840-dk")]
+        let magic: Option<u8> = None;
+        if let Some(magic) = magic {
+            let power = unsafe { &*pac::POWER::ptr() };
+            power.gpregret.write(|w| unsafe { w.gpregret().bits(magic) });
+        }
+        pac::SCB::sys_reset()
+    }
+
+    fn device_id(&self) -> &'static [u8] {
+        // The 64-bit device identifier is in the factory information registers.
+        let ficr = unsafe { &*pac::FICR::ptr() };
+        let deviceid = ficr.deviceid.as_ptr() as *const u8;
+        unsafe { core::slice::from_raw_parts(deviceid, 8) }
+    }
+
+    fn flash(&mut self) -> Flash {
+        let region = super::settings_region();
+        let pages = region.len() / <Flash as onekibu::store::Flash>::PAGE;
+        Flash { start: region.start, pages }
+    }
+}
+
+impl Flash {
+    fn nvmc() -> &'static pac::nvmc::RegisterBlock {
+        // The NVMC is only used for the settings region.
+        unsafe { &*pac::NVMC::ptr() }
+    }
+
+    fn wait_ready() {
+        while Flash::nvmc().ready.read().ready().is_busy() {}
+    }
+}
+
+impl onekibu::store::Flash for Flash {
+    const PAGE: usize = 4096;
+
+    fn pages(&self) -> usize {
+        self.pages
+    }
+
+    fn read(&self, offset: usize, data: &mut [u8]) {
+        super::read_flash(self.start + offset, data);
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), onekibu::store::Error> {
+        let nvmc = Flash::nvmc();
+        nvmc.config.write(|w| w.wen().wen());
+        for (i, word) in data.chunks_exact(4).enumerate() {
+            let address = (self.start + offset + 4 * i) as *mut u32;
+            let word = u32::from_le_bytes(word.try_into().unwrap());
+            unsafe { address.write_volatile(word) };
+            Flash::wait_ready();
+        }
+        nvmc.config.write(|w| w.wen().ren());
+        Ok(())
+    }
+
+    fn erase(&mut self, page: usize) -> Result<(), onekibu::store::Error> {
+        let nvmc = Flash::nvmc();
+        nvmc.config.write(|w| w.wen().een());
+        let address = self.start + page * Self::PAGE;
+        nvmc.erasepage().write(|w| unsafe { w.bits(address as u32) });
+        Flash::wait_ready();
+        nvmc.config.write(|w| w.wen().ren());
+        Ok(())
+    }
+}
+
+impl Mono {
+    fn new(rtc: pac::RTC1) -> Mono {
+        // The overflow event is only read, while the compare event interrupts.
+        rtc.evtenset.write(|w| w.ovrflw().set_bit());
+        rtc.intenset.write(|w| w.compare0().set_bit());
+        Mono { rtc, overflows: 0 }
+    }
+}
+
+impl Monotonic for Mono {
+    type Instant = fugit::TimerInstantU64<32_768>;
+    type Duration = fugit::TimerDurationU64<32_768>;
+
+    /// Returns the current instant, extending the 24-bit counter with its overflows.
+    ///
+    /// Overflows are counted here instead of in an interrupt. While a task is scheduled, the
+    /// compare interrupt fires at least once per overflow, so none is missed. While the queue is
+    /// empty, missing overflows only slows down time, which stays monotonic.
+    fn now(&mut self) -> Self::Instant {
+        loop {
+            if self.rtc.events_ovrflw.read().bits() != 0 {
+                self.rtc.events_ovrflw.reset();
+                self.overflows += 1;
+            }
+            let counter = self.rtc.counter.read().bits() as u64;
+            // Read again if the counter overflowed in between.
+            if self.rtc.events_ovrflw.read().bits() == 0 {
+                return Self::Instant::from_ticks(self.overflows << 24 | counter);
+            }
+        }
+    }
+
+    fn set_compare(&mut self, instant: Self::Instant) {
+        // The compare event may not trigger within 2 ticks of the counter.
+        let instant = core::cmp::max(instant, self.now() + Self::Duration::from_ticks(2));
+        self.rtc.cc[0].write(|w| unsafe { w.bits(instant.ticks() as u32 & 0xff_ffff) });
+    }
+
+    fn clear_compare_flag(&mut self) {
+        self.rtc.events_compare[0].reset();
+    }
+
+    fn zero() -> Self::Instant {
+        Self::Instant::from_ticks(0)
+    }
+
+    unsafe fn reset(&mut self) {
+        self.rtc.tasks_clear.write(|w| w.bits(1));
+        self.rtc.tasks_start.write(|w| w.bits(1));
+    }
 }
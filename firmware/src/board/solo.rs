@@ -12,8 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use stm32l4xx_hal::delay::Delay;
-use stm32l4xx_hal::gpio::{gpioa::PA0, EPin, Input, Output, PullUp, PushPull};
+use rtic::Monotonic;
+use stm32l4xx_hal::gpio::{gpioa::PA0, EPin, Edge, ExtiPin, Input, Output, PullUp, PushPull};
 use stm32l4xx_hal::prelude::*;
 use stm32l4xx_hal::stm32;
 use stm32l4xx_hal::usb::{Peripheral, UsbBus};
@@ -21,32 +21,36 @@ use usb_device::class_prelude::UsbBusAllocator;
 
 pub use stm32l4xx_hal::pac;
 
+/// Settings region of the flash, programmed by double words.
+pub struct Flash {
+    start: usize,
+    pages: usize,
+}
+
+/// Monotonic timer on TIM2, which only interrupts when a task is due.
+///
+/// TIM2 is a 32-bit timer running at the 8MHz system clock, also while sleeping (unlike the cycle
+/// counter). The board timestamps inputs with its counter too.
+pub struct Mono {
+    timer: pac::TIM2,
+    /// Number of counter overflows (see [`Mono::now()`]).
+    overflows: u64,
+}
+
 pub struct Board {
     button: PA0<Input<PullUp>>,
     leds: [EPin<Output<PushPull>>; 3],
-    timer: Delay,
 }
 
 static mut USB_BUS: Option<UsbBusAllocator<UsbBus<Peripheral>>> = None;
 
-impl Board {
-    pub fn blink(&mut self, i: usize) {
-        for _ in 0 .. 3 {
-            self.leds[i].set_low();
-            self.timer.delay_ms(100u32);
-            self.leds[i].set_high();
-            self.timer.delay_ms(100u32);
-        }
-    }
-}
-
 impl super::BoardApi for Board {
     type UsbBus = UsbBus<Peripheral>;
+    type Flash = Flash;
+    // The Solo bootloader is only entered by holding the button while plugging.
+    const BOOTLOADER: bool = false;
 
-    fn new(mut c: rtic::export::Peripherals, p: pac::Peripherals) -> Board {
-        c.DCB.enable_trace();
-        pac::DWT::unlock();
-        c.DWT.enable_cycle_counter();
+    fn new(_: rtic::export::Peripherals, p: pac::Peripherals) -> (Board, Mono) {
         let mut flash = p.FLASH.constrain();
         let mut rcc = p.RCC.constrain();
         let mut pwr = p.PWR.constrain(&mut rcc.apb1r1);
@@ -78,10 +82,17 @@ impl super::BoardApi for Board {
             // Enable VddUSB
             let pwr = unsafe { &*stm32::PWR::ptr() };
             pwr.cr2.modify(|_, w| w.usv().set_bit());
+
+            // Enable TIM2 (started with the monotonic).
+            rcc.apb1enr1.modify(|_, w| w.tim2en().set_bit());
         }
 
         let mut gpioa = p.GPIOA.split(&mut rcc.ahb2);
-        let button = gpioa.pa0.into_pull_up_input(&mut gpioa.moder, &mut gpioa.pupdr);
+        let mut button = gpioa.pa0.into_pull_up_input(&mut gpioa.moder, &mut gpioa.pupdr);
+        let (mut exti, mut syscfg) = (p.EXTI, p.SYSCFG);
+        button.make_interrupt_source(&mut syscfg, &mut rcc.apb2);
+        button.trigger_on_edge(&mut exti, Edge::RisingFalling);
+        button.enable_interrupt(&mut exti);
         let mut leds = [
             gpioa.pa2.into_push_pull_output(&mut gpioa.moder, &mut gpioa.otyper).erase(),
             gpioa.pa3.into_push_pull_output(&mut gpioa.moder, &mut gpioa.otyper).erase(),
@@ -91,7 +102,6 @@ impl super::BoardApi for Board {
         for led in leds.iter_mut() {
             led.set_high();
         }
-        let timer = Delay::new(c.SYST, clocks);
         let usb = Peripheral {
             usb: p.USB,
             pin_dm: gpioa.pa11.into_alternate(&mut gpioa.moder, &mut gpioa.otyper, &mut gpioa.afrh),
@@ -100,7 +110,9 @@ impl super::BoardApi for Board {
         unsafe {
             USB_BUS = Some(UsbBus::new(usb));
         }
-        Board { button, leds, timer }
+        // The timestamps and the monotonic assume that TIM2 runs at 8MHz.
+        assert_eq!(clocks.pclk1().raw(), 8_000_000);
+        (Board { button, leds }, Mono::new(p.TIM2))
     }
 
     fn usb_bus(&self) -> &'static UsbBusAllocator<Self::UsbBus> {
@@ -108,19 +120,60 @@ impl super::BoardApi for Board {
     }
 
     fn config(&self) -> onekibu::Config {
-        let period = 1000000;
-        onekibu::Config { maximum: u32::MAX as usize, period }
+        let ms = onekibu::Duration::from_millis;
+        let period = 125;
+        let debounce = onekibu::Debounce::Integrator(ms(5));
+        let modifier = onekibu::ModifierMode::OneShot;
+        onekibu::Config {
+            // The timer runs at the 8MHz system clock.
+            clock: onekibu::Clock { frequency: 8_000_000, maximum: u32::MAX as usize },
+            long: ms(period),
+            cancel: ms(2 * period),
+            letter: ms(period),
+            word: None,
+            repeat: Some(onekibu::Repeat {
+                window: ms(period),
+                delay: ms(3 * period / 2),
+                interval: ms(period / 2),
+            }),
+            adaptive: None,
+            debounce,
+            lag: onekibu::Lag::Clamp,
+            modifier,
+        }
+    }
+
+    fn now(&self) -> onekibu::Instant {
+        // The timer is owned by the monotonic, but reading its counter has no side effect.
+        let timer = unsafe { &*pac::TIM2::ptr() };
+        onekibu::Instant::from_ticks(timer.cnt.read().bits() as usize)
     }
 
     fn input(&self) -> onekibu::Input {
-        onekibu::Input { timestamp: pac::DWT::cycle_count() as usize, button: self.button.is_low() }
+        onekibu::Input { timestamp: self.now(), button: self.button.is_low() }
+    }
+
+    fn clear_button(&mut self) {
+        self.button.clear_interrupt_pending_bit();
+    }
+
+    fn usb_wakeup(&mut self) {
+        // The USB peripheral is owned by the USB bus, which doesn't support remote wakeup.
+        let usb = unsafe { &*pac::USB::ptr() };
+        usb.cntr.modify(|_, w| w.fsusp().clear_bit().lpmode().clear_bit().resume().set_bit());
+    }
+
+    fn usb_wakeup_end(&mut self) {
+        let usb = unsafe { &*pac::USB::ptr() };
+        usb.cntr.modify(|_, w| w.resume().clear_bit());
     }
 
-    fn state(&mut self, state: onekibu::BitState) {
+    fn state(&mut self, state: onekibu::BitState, _modifiers: u8) {
         let bits = match state {
-            onekibu::BitState::Ready => [0, 0, 0],
+            onekibu::BitState::Ready | onekibu::BitState::Gap => [0, 0, 0],
             onekibu::BitState::Short => [0, 1, 0],
             onekibu::BitState::Long => [0, 1, 1],
+            onekibu::BitState::Repeat => [1, 1, 1],
             onekibu::BitState::Cancel => [1, 0, 0],
             onekibu::BitState::Done => [0, 0, 1],
         };
@@ -133,4 +186,158 @@ impl super::BoardApi for Board {
             }
         }
     }
+
+    fn host_leds(&mut self, _leds: onekibu::HostLeds) {
+        // All LEDs are used to show the state.
+    }
+
+    fn reboot_bootloader(&mut self) -> ! {
+        unreachable!()
+    }
+
+    fn device_id(&self) -> &'static [u8] {
+        // The 96-bit unique device identifier is in the system memory.
+        const UID: usize = 0x1fff_7590;
+        unsafe { core::slice::from_raw_parts(UID as *const u8, 12) }
+    }
+
+    fn flash(&mut self) -> Flash {
+        let region = super::settings_region();
+        let pages = region.len() / <Flash as onekibu::store::Flash>::PAGE;
+        Flash { start: region.start, pages }
+    }
+}
+
+impl Flash {
+    /// Base address of the flash (pages are numbered from it).
+    const BASE: usize = 0x0800_0000;
+
+    /// Unlocks the flash control register and clears previous errors.
+    fn unlock() -> &'static pac::flash::RegisterBlock {
+        let flash = unsafe { &*pac::FLASH::ptr() };
+        if flash.cr.read().lock().bit_is_set() {
+            flash.keyr.write(|w| unsafe { w.keyr().bits(0x4567_0123) });
+            flash.keyr.write(|w| unsafe { w.keyr().bits(0xcdef_89ab) });
+        }
+        // Error flags are cleared by writing them.
+        flash.sr.write(|w| unsafe { w.bits(flash.sr.read().bits()) });
+        flash
+    }
+
+    fn lock(flash: &pac::flash::RegisterBlock) {
+        while flash.sr.read().bsy().bit_is_set() {}
+        flash.cr.write(|w| w.lock().set_bit());
+    }
+
+    /// Waits for the current operation and returns whether it failed (clearing its errors).
+    fn failed(flash: &pac::flash::RegisterBlock) -> bool {
+        // OPTVERR, RDERR, FASTERR, MISERR, PGSERR, SIZERR, PGAERR, WRPERR, PROGERR, and OPERR.
+        const ERRORS: u32 = 0xc3fa;
+        while flash.sr.read().bsy().bit_is_set() {}
+        let errors = flash.sr.read().bits() & ERRORS;
+        flash.sr.write(|w| unsafe { w.bits(errors) });
+        errors != 0
+    }
+}
+
+impl onekibu::store::Flash for Flash {
+    const PAGE: usize = 2048;
+
+    fn pages(&self) -> usize {
+        self.pages
+    }
+
+    fn read(&self, offset: usize, data: &mut [u8]) {
+        super::read_flash(self.start + offset, data);
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), onekibu::store::Error> {
+        let flash = Flash::unlock();
+        flash.cr.write(|w| w.pg().set_bit());
+        let mut result = Ok(());
+        for (i, double) in data.chunks_exact(8).enumerate() {
+            let address = (self.start + offset + 8 * i) as *mut u32;
+            for (j, word) in double.chunks_exact(4).enumerate() {
+                let word = u32::from_le_bytes(word.try_into().unwrap());
+                unsafe { address.add(j).write_volatile(word) };
+            }
+            if Flash::failed(flash) {
+                result = Err(onekibu::store::Error::Write);
+                break;
+            }
+        }
+        Flash::lock(flash);
+        result
+    }
+
+    fn erase(&mut self, page: usize) -> Result<(), onekibu::store::Error> {
+        let flash = Flash::unlock();
+        let page = (self.start - Flash::BASE) / Self::PAGE + page;
+        flash.cr.write(|w| unsafe { w.per().set_bit().pnb().bits(page as u8) });
+        flash.cr.modify(|_, w| w.start().set_bit());
+        let failed = Flash::failed(flash);
+        Flash::lock(flash);
+        // The data cache may still hold the previous content of the page (see FLASH_FlushCaches in
+        // the ST HAL).
+        if flash.acr.read().dcen().bit_is_set() {
+            flash.acr.modify(|_, w| w.dcen().clear_bit());
+            flash.acr.modify(|_, w| w.dcrst().set_bit());
+            flash.acr.modify(|_, w| w.dcrst().clear_bit());
+            flash.acr.modify(|_, w| w.dcen().set_bit());
+        }
+        if failed {
+            return Err(onekibu::store::Error::Erase);
+        }
+        Ok(())
+    }
+}
+
+impl Mono {
+    fn new(timer: pac::TIM2) -> Mono {
+        // The timer counts up to u32::MAX without prescaler by default.
+        timer.dier.write(|w| w.cc1ie().set_bit());
+        Mono { timer, overflows: 0 }
+    }
+}
+
+impl Monotonic for Mono {
+    type Instant = fugit::TimerInstantU64<8_000_000>;
+    type Duration = fugit::TimerDurationU64<8_000_000>;
+
+    /// Returns the current instant, extending the 32-bit counter with its overflows.
+    ///
+    /// Overflows are counted here instead of in an interrupt. While a task is scheduled, the
+    /// compare interrupt fires at least once per overflow, so none is missed. While the queue is
+    /// empty, missing overflows only slows down time, which stays monotonic.
+    fn now(&mut self) -> Self::Instant {
+        loop {
+            if self.timer.sr.read().uif().bit_is_set() {
+                // Flags are cleared by writing zero (writing one has no effect).
+                self.timer.sr.write(|w| unsafe { w.bits(!1) });
+                self.overflows += 1;
+            }
+            let counter = self.timer.cnt.read().bits() as u64;
+            // Read again if the counter overflowed in between.
+            if self.timer.sr.read().uif().bit_is_clear() {
+                return Self::Instant::from_ticks(self.overflows << 32 | counter);
+            }
+        }
+    }
+
+    fn set_compare(&mut self, instant: Self::Instant) {
+        self.timer.ccr1.write(|w| unsafe { w.bits(instant.ticks() as u32) });
+    }
+
+    fn clear_compare_flag(&mut self) {
+        self.timer.sr.write(|w| unsafe { w.bits(!(1 << 1)) });
+    }
+
+    fn zero() -> Self::Instant {
+        Self::Instant::from_ticks(0)
+    }
+
+    unsafe fn reset(&mut self) {
+        self.timer.cnt.reset();
+        self.timer.cr1.modify(|_, w| w.cen().set_bit());
+    }
 }
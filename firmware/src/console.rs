@@ -0,0 +1,265 @@
+// Copyright 2021-2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Parses and formats the lines of the configuration console.
+
+use core::fmt::{self, Write};
+use defmt::Format;
+
+use crate::{keymap, Config, Duration, Keymap, Repeat, Stats};
+
+/// Command of a console line.
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Lists the commands.
+    Help,
+
+    /// Shows the mapped sequences of each layer.
+    Keymap,
+
+    /// Shows the actions of each chord and macro.
+    Macros,
+
+    /// Shows the durations.
+    Timings,
+
+    /// Sets a duration.
+    Set(Setting),
+
+    /// Shows the usage statistics.
+    Stats,
+
+    /// Reboots to the bootloader (if the board has one).
+    Reboot,
+}
+
+/// Duration of the configuration.
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Setting {
+    Long(Duration),
+    Cancel(Duration),
+    Letter(Duration),
+    Word(Option<Duration>),
+    Repeat(Option<Repeat>),
+}
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The command does not exist.
+    UnknownCommand,
+
+    /// The duration does not exist.
+    UnknownSetting,
+
+    /// The duration is not a positive number of milliseconds (or `off` for the word gap and repeat).
+    InvalidDuration,
+
+    /// The command has missing or extra arguments.
+    InvalidArguments,
+
+    /// The durations would be inconsistent (see [`Config::is_valid()`]).
+    InvalidTimings,
+
+    /// The command is not supported by the board.
+    Unsupported,
+}
+
+/// Usage of each command.
+const HELP: &[&str] = &[
+    "help: Lists the commands",
+    "keymap: Shows the mapped sequences of each layer",
+    "macros: Shows the actions of each chord and macro",
+    "timings: Shows the durations in milliseconds",
+    "set long|cancel|letter <ms>: Sets a duration",
+    "set word <ms>|off: Sets or disables the word gap",
+    "set repeat <window> <delay> <interval>|off: Sets or disables the repeat gesture",
+    "stats: Shows the usage statistics",
+    "reboot: Reboots to the bootloader (if the board has one)",
+];
+
+/// Parses a line (e.g. `"set long 200"`).
+pub fn parse(line: &str) -> Result<Command, Error> {
+    let mut words = line.split_whitespace();
+    let mut next = || words.next().ok_or(Error::InvalidArguments);
+    let command = match next().map_err(|_| Error::UnknownCommand)? {
+        "help" => Command::Help,
+        "keymap" => Command::Keymap,
+        "macros" => Command::Macros,
+        "timings" => Command::Timings,
+        "set" => {
+            let name = next()?;
+            let value = next()?;
+            let duration = |value: &str| match value.parse() {
+                Ok(0) | Err(_) => Err(Error::InvalidDuration),
+                Ok(millis) => Ok(Duration::from_millis(millis)),
+            };
+            Command::Set(match name {
+                "long" => Setting::Long(duration(value)?),
+                "cancel" => Setting::Cancel(duration(value)?),
+                "letter" => Setting::Letter(duration(value)?),
+                "word" if value == "off" => Setting::Word(None),
+                "word" => Setting::Word(Some(duration(value)?)),
+                "repeat" if value == "off" => Setting::Repeat(None),
+                "repeat" => Setting::Repeat(Some(Repeat {
+                    window: duration(value)?,
+                    delay: duration(next()?)?,
+                    interval: duration(next()?)?,
+                })),
+                _ => return Err(Error::UnknownSetting),
+            })
+        }
+        "stats" => Command::Stats,
+        "reboot" => Command::Reboot,
+        _ => return Err(Error::UnknownCommand),
+    };
+    if words.next().is_some() {
+        return Err(Error::InvalidArguments);
+    }
+    Ok(command)
+}
+
+impl Setting {
+    /// Sets the duration in a configuration, unless the durations would be inconsistent.
+    pub fn apply(self, config: &mut Config) -> Result<(), Error> {
+        let mut result = *config;
+        match self {
+            Setting::Long(x) => result.long = x,
+            Setting::Cancel(x) => result.cancel = x,
+            Setting::Letter(x) => result.letter = x,
+            Setting::Word(x) => result.word = x,
+            Setting::Repeat(x) => result.repeat = x,
+        }
+        if !result.is_valid() {
+            return Err(Error::InvalidTimings);
+        }
+        *config = result;
+        Ok(())
+    }
+}
+
+pub fn help(out: &mut impl Write) -> fmt::Result {
+    HELP.iter().try_for_each(|line| writeln!(out, "{line}"))
+}
+
+pub fn error(out: &mut impl Write, error: Error) -> fmt::Result {
+    writeln!(out, "error: {error:?} (try help)")
+}
+
+pub fn keymap(out: &mut impl Write, keymap: &Keymap) -> fmt::Result {
+    for layer in 0 .. keymap.layers() as u8 {
+        writeln!(out, "layer {layer}:")?;
+        for sequence in 1 .. keymap::LENGTH {
+            if let Some(action) = keymap.get(layer, sequence) {
+                out.write_str("  ")?;
+                write_sequence(out, sequence)?;
+                writeln!(out, " {action:?}")?;
+            }
+        }
+    }
+    writeln!(out, "word: {:?}", keymap.word())
+}
+
+pub fn macros(out: &mut impl Write, keymap: &Keymap) -> fmt::Result {
+    for chord in 0 .. keymap.chords() as u8 {
+        writeln!(out, "chord {chord}: {:?}", keymap.chord(chord))?;
+    }
+    for macro_ in 0 .. keymap.macros() as u8 {
+        writeln!(out, "macro {macro_}: {:?}", keymap.macro_(macro_))?;
+    }
+    Ok(())
+}
+
+pub fn timings(out: &mut impl Write, config: &Config) -> fmt::Result {
+    writeln!(out, "long {}", config.long.millis())?;
+    writeln!(out, "cancel {}", config.cancel.millis())?;
+    writeln!(out, "letter {}", config.letter.millis())?;
+    match config.word {
+        Some(word) => writeln!(out, "word {}", word.millis())?,
+        None => writeln!(out, "word off")?,
+    }
+    match config.repeat {
+        Some(Repeat { window, delay, interval }) => {
+            writeln!(out, "repeat {} {} {}", window.millis(), delay.millis(), interval.millis())
+        }
+        None => writeln!(out, "repeat off"),
+    }
+}
+
+pub fn stats(out: &mut impl Write, stats: &Stats) -> fmt::Result {
+    writeln!(out, "presses {}", stats.presses)?;
+    writeln!(out, "actions {}", stats.actions)?;
+    writeln!(out, "cancels {}", stats.cancels)?;
+    writeln!(out, "max lag {}ms", stats.max_lag.millis())
+}
+
+/// Writes a sequence given its index (see [`keymap::parse()`]).
+fn write_sequence(out: &mut impl Write, sequence: usize) -> fmt::Result {
+    if sequence == 0 {
+        return Ok(());
+    }
+    let (parent, bit) = match sequence % 2 {
+        1 => (sequence / 2, '.'),
+        _ => (sequence / 2 - 1, '-'),
+    };
+    write_sequence(out, parent)?;
+    out.write_char(bit)
+}
+
+#[test]
+fn parse_commands() {
+    use Setting::*;
+    let ms = Duration::from_millis;
+    assert_eq!(parse(" keymap "), Ok(Command::Keymap));
+    assert_eq!(parse("set long 200"), Ok(Command::Set(Long(ms(200)))));
+    assert_eq!(parse("set word off"), Ok(Command::Set(Word(None))));
+    assert_eq!(parse("set word 700"), Ok(Command::Set(Word(Some(ms(700))))));
+    let repeat = crate::Repeat { window: ms(160), delay: ms(240), interval: ms(80) };
+    assert_eq!(parse("set repeat 160 240 80"), Ok(Command::Set(Repeat(Some(repeat)))));
+    assert_eq!(parse("set repeat off"), Ok(Command::Set(Repeat(None))));
+    assert_eq!(parse(""), Err(Error::UnknownCommand));
+    assert_eq!(parse("flash"), Err(Error::UnknownCommand));
+    assert_eq!(parse("set dit 80"), Err(Error::UnknownSetting));
+    assert_eq!(parse("set long off"), Err(Error::InvalidDuration));
+    assert_eq!(parse("set cancel 0"), Err(Error::InvalidDuration));
+    assert_eq!(parse("set letter"), Err(Error::InvalidArguments));
+    assert_eq!(parse("set repeat 160 240"), Err(Error::InvalidArguments));
+    assert_eq!(parse("set repeat 160 0 80"), Err(Error::InvalidDuration));
+    assert_eq!(parse("stats now"), Err(Error::InvalidArguments));
+}
+
+#[test]
+fn apply_settings() {
+    let ms = Duration::from_millis;
+    let mut config = crate::test_config();
+    assert_eq!(Setting::Cancel(ms(300)).apply(&mut config), Ok(()));
+    assert_eq!(config.cancel, ms(300));
+    assert_eq!(Setting::Long(ms(300)).apply(&mut config), Err(Error::InvalidTimings));
+    assert_eq!(Setting::Word(Some(ms(50))).apply(&mut config), Err(Error::InvalidTimings));
+    assert_eq!((config.long, config.word), (ms(100), None));
+}
+
+#[test]
+fn format_keymap() {
+    use crate::Action;
+    let mut builder = Keymap::builder();
+    builder.map(".-", Action::Tap(4)).unwrap().text("--.", "ab").unwrap();
+    builder.layer(1).map("-", Action::Cancel).unwrap();
+    let keymap = builder.build();
+    let mut out = std::string::String::new();
+    self::keymap(&mut out, &keymap).unwrap();
+    macros(&mut out, &keymap).unwrap();
+    let expected = "layer 0:\n  .- Tap(4)\n  --. Macro(0)\nlayer 1:\n  - Cancel\nword: Tap(44)\n\
+                    macro 0: [Tap(4), Tap(5)]\n";
+    assert_eq!(out, expected);
+}
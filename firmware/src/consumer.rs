@@ -0,0 +1,25 @@
+// Copyright 2021-2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Usages of the consumer page (e.g. media keys) for [`Action::Consumer`](crate::Action).
+
+pub const BRIGHTNESS_UP: u16 = 0x6f;
+pub const BRIGHTNESS_DOWN: u16 = 0x70;
+pub const NEXT_TRACK: u16 = 0xb5;
+pub const PREVIOUS_TRACK: u16 = 0xb6;
+pub const STOP: u16 = 0xb7;
+pub const PLAY_PAUSE: u16 = 0xcd;
+pub const MUTE: u16 = 0xe2;
+pub const VOLUME_UP: u16 = 0xe9;
+pub const VOLUME_DOWN: u16 = 0xea;
@@ -0,0 +1,157 @@
+// Copyright 2021-2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use defmt::Format;
+
+use crate::{Config, Duration, Input, Instant};
+
+/// Debouncing strategy.
+///
+/// See https://github.com/TyberiusPrime/debouncing for some background.
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Debounce {
+    /// The raw button state is used as is.
+    None,
+
+    /// The button state changes once the raw state has been integrated for this duration.
+    ///
+    /// The integral grows while the raw button is pressed and shrinks while it is released. The
+    /// button state is pressed when the integral reaches the duration and released when it reaches
+    /// zero.
+    Integrator(Duration),
+
+    /// The button state follows the raw state but is locked for this duration after a change.
+    LockOut(Duration),
+}
+
+pub struct DebounceLayer {
+    config: Config,
+    /// Debounced button state.
+    button: bool,
+    /// Previous raw button state (only used by the integrator).
+    raw: bool,
+    /// Integral of the raw button state (only used by the integrator).
+    integral: usize,
+    /// Timestamp of the last debounced change (only used by the lock-out).
+    reference: Instant,
+    /// Previous timestamp.
+    previous: Instant,
+}
+
+impl DebounceLayer {
+    pub fn new(config: Config) -> DebounceLayer {
+        let (reference, previous) = (Instant::default(), Instant::default());
+        DebounceLayer { config, button: false, raw: false, integral: 0, reference, previous }
+    }
+
+    pub fn step(&mut self, input: Input) -> Input {
+        let clock = self.config.clock;
+        let elapsed = clock.elapsed(self.previous, input.timestamp);
+        self.previous = input.timestamp;
+        match self.config.debounce {
+            Debounce::None => self.button = input.button,
+            Debounce::Integrator(duration) => {
+                let duration = clock.ticks(duration);
+                // The raw state is assumed to hold until the next sample.
+                if self.raw {
+                    self.integral = core::cmp::min(self.integral.saturating_add(elapsed), duration);
+                } else {
+                    self.integral = self.integral.saturating_sub(elapsed);
+                }
+                self.raw = input.button;
+                if self.integral == 0 {
+                    self.button = false;
+                } else if self.integral == duration {
+                    self.button = true;
+                }
+            }
+            Debounce::LockOut(duration) => {
+                let locked = clock.elapsed(self.reference, input.timestamp) < clock.ticks(duration);
+                if !locked && input.button != self.button {
+                    self.button = input.button;
+                    self.reference = input.timestamp;
+                }
+            }
+        }
+        Input { timestamp: input.timestamp, button: self.button }
+    }
+}
+
+#[cfg(test)]
+fn replay(debounce: Debounce, trace: &[(usize, bool)]) -> [bool; 32] {
+    let clock = crate::Clock { frequency: 1000, maximum: 999 };
+    let config = Config { clock, debounce, ..crate::test_config() };
+    let mut layer = DebounceLayer::new(config);
+    let mut output = [false; 32];
+    for (i, &(timestamp, button)) in trace.iter().enumerate() {
+        output[i] = layer.step(Input { timestamp: Instant::from_ticks(timestamp), button }).button;
+    }
+    output
+}
+
+/// A press bouncing for 4 units followed by a release bouncing for 3 units.
+#[cfg(test)]
+const BOUNCY: [(usize, bool); 18] = [
+    (10, false),
+    (11, true),
+    (12, false),
+    (13, true),
+    (14, false),
+    (15, true),
+    (16, true),
+    (17, true),
+    (18, true),
+    (19, true),
+    (30, false),
+    (31, true),
+    (32, false),
+    (33, true),
+    (34, false),
+    (35, false),
+    (36, false),
+    (37, false),
+];
+
+#[test]
+fn none() {
+    let output = replay(Debounce::None, &BOUNCY);
+    for (i, &(_, button)) in BOUNCY.iter().enumerate() {
+        assert_eq!(output[i], button);
+    }
+}
+
+#[test]
+fn integrator() {
+    let output = replay(Debounce::Integrator(Duration::from_millis(3)), &BOUNCY);
+    let expected = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0];
+    for (i, &x) in expected.iter().enumerate() {
+        assert_eq!(output[i], x == 1, "at {}", i);
+    }
+}
+
+#[test]
+fn integrator_wrap() {
+    let trace = [(997, true), (998, true), (999, true), (0, true), (1, false), (5, false)];
+    let output = replay(Debounce::Integrator(Duration::from_millis(3)), &trace);
+    assert_eq!(output[.. 6], [false, false, false, true, true, false]);
+}
+
+#[test]
+fn lock_out() {
+    let output = replay(Debounce::LockOut(Duration::from_millis(5)), &BOUNCY);
+    let expected = [0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0];
+    for (i, &x) in expected.iter().enumerate() {
+        assert_eq!(output[i], x == 1, "at {}", i);
+    }
+}
@@ -0,0 +1,722 @@
+// Copyright 2021-2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use defmt::Format;
+
+use crate::{ascii, Action};
+
+/// Number of sequences that can be mapped.
+///
+/// Those are the sequences of 1 to 7 bits. The empty sequence is reserved to cancel and sequences
+/// of 8 bits are reserved for low-level sequences (the keycode is directly given by the bits).
+pub const LENGTH: usize = 255;
+
+/// Maximum number of chords and macros nested in a chord or macro.
+///
+/// Chords and macros are run recursively, so this bounds the stack usage.
+pub const MAX_DEPTH: usize = 8;
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The sequence contains characters other than `.` and `-`.
+    InvalidSequence,
+
+    /// The sequence is reserved (empty or too long).
+    Reserved,
+
+    /// The sequence is already mapped.
+    Duplicate,
+
+    /// The chord does not exist.
+    InvalidChord,
+
+    /// There are too many chords.
+    TooManyChords,
+
+    /// The macro does not exist.
+    InvalidMacro,
+
+    /// There are too many macros.
+    TooManyMacros,
+
+    /// The layer does not exist.
+    InvalidLayer,
+
+    /// The text contains a character that cannot be typed.
+    InvalidCharacter,
+
+    /// The chord or macro would run itself (possibly through other chords and macros).
+    Cycle,
+
+    /// A chord or macro would nest more than [`MAX_DEPTH`] chords and macros.
+    TooDeep,
+}
+
+/// Parses a sequence of `.` and `-` into its index.
+///
+/// The index of a sequence is obtained by starting from 0 and then, for each bit, doubling and
+/// adding 1 for `.` and 2 for `-`.
+pub fn parse(sequence: &str) -> Result<usize, Error> {
+    if sequence.bytes().any(|x| x != b'.' && x != b'-') {
+        return Err(Error::InvalidSequence);
+    }
+    if sequence.is_empty() || sequence.len() > 7 {
+        return Err(Error::Reserved);
+    }
+    Ok(sequence.bytes().fold(0, |r, x| 2 * r + if x == b'.' { 1 } else { 2 }))
+}
+
+/// Maps sequences to actions for each layer.
+///
+/// Layer 0 is the base layer. Unmapped sequences in a layer fall back to the layers below (see
+/// [`Action::Layer`] and [`Action::ToggleLayer`]).
+#[derive(Clone, PartialEq, Eq)]
+pub struct Keymap {
+    /// Action of each sequence, indexed by layer then sequence.
+    layers: Vec<Vec<Option<Action>>>,
+
+    /// Actions of each chord, indexed by chord.
+    chords: Vec<Vec<Action>>,
+
+    /// Actions of each macro, indexed by macro.
+    macros: Vec<Vec<Action>>,
+
+    /// Action of word gaps.
+    word: Action,
+}
+
+impl Keymap {
+    /// Creates an empty keymap builder.
+    pub fn builder() -> Builder {
+        let layers = vec![vec![None; LENGTH]];
+        let keymap = Keymap { layers, chords: Vec::new(), macros: Vec::new(), word: SPACE };
+        Builder { keymap, layer: 0 }
+    }
+
+    /// Creates a single-layer keymap from a table indexed by sequence.
+    ///
+    /// Mapped sequences tap their keycode. Unmapped sequences have keycode 0. Missing sequences at
+    /// the end of the table are not mapped.
+    pub fn from_table(table: &[u8]) -> Result<Keymap, Error> {
+        if table.len() > LENGTH || table.first().is_some_and(|&x| x != 0) {
+            return Err(Error::Reserved);
+        }
+        let mut builder = Keymap::builder();
+        for (sequence, &key) in table.iter().enumerate().filter(|(_, &x)| x != 0) {
+            builder.keymap.layers[0][sequence] = Some(Action::from(key));
+        }
+        Ok(builder.build())
+    }
+
+    /// Returns the number of layers.
+    pub fn layers(&self) -> usize {
+        self.layers.len()
+    }
+
+    /// Returns the action of a sequence in a layer, if mapped.
+    ///
+    /// This does not fall back to other layers.
+    pub fn get(&self, layer: u8, sequence: usize) -> Option<Action> {
+        self.layers.get(layer as usize)?.get(sequence).cloned().flatten()
+    }
+
+    /// Returns the number of chords.
+    pub fn chords(&self) -> usize {
+        self.chords.len()
+    }
+
+    /// Returns the number of macros.
+    pub fn macros(&self) -> usize {
+        self.macros.len()
+    }
+
+    /// Returns the actions of a chord.
+    ///
+    /// Chords are run within a sequence, such that their keys are released at the end.
+    pub fn chord(&self, chord: u8) -> &[Action] {
+        &self.chords[chord as usize]
+    }
+
+    /// Returns the actions of a macro.
+    ///
+    /// Macros are run outside sequences, such that taps are taps.
+    pub fn macro_(&self, macro_: u8) -> &[Action] {
+        &self.macros[macro_ as usize]
+    }
+
+    /// Sets the action of a sequence in a layer (or unmaps it).
+    ///
+    /// The layer is created if it is the next one.
+    pub fn set(&mut self, layer: u8, sequence: usize, action: Option<Action>) -> Result<(), Error> {
+        if sequence == 0 || sequence >= LENGTH {
+            return Err(Error::Reserved);
+        }
+        if layer as usize > self.layers.len() {
+            return Err(Error::InvalidLayer);
+        }
+        if let Some(action) = action {
+            self.check(action)?;
+        }
+        if layer as usize == self.layers.len() {
+            self.layers.push(vec![None; LENGTH]);
+        }
+        self.layers[layer as usize][sequence] = action;
+        Ok(())
+    }
+
+    /// Sets the action of word gaps.
+    pub fn set_word(&mut self, action: Action) -> Result<(), Error> {
+        self.check(action)?;
+        self.word = action;
+        Ok(())
+    }
+
+    /// Replaces the actions of a chord from an offset.
+    ///
+    /// The chord is created if it is the next one. It may only use chords before it and macros
+    /// that don't run it.
+    pub fn set_chord(&mut self, chord: u8, offset: usize, actions: &[Action]) -> Result<(), Error> {
+        for &action in actions {
+            match action {
+                Action::Chord(x) if x >= chord => return Err(Error::InvalidChord),
+                action => self.check(action)?,
+            }
+        }
+        self.check_cycle(actions, Action::Chord(chord))?;
+        self.check_depth(actions, Action::Chord(chord))?;
+        if chord as usize == self.chords.len() {
+            self.chords.push(Vec::new());
+        }
+        let chord = self.chords.get_mut(chord as usize).ok_or(Error::InvalidChord)?;
+        replace(chord, offset, actions).ok_or(Error::InvalidChord)
+    }
+
+    /// Replaces the actions of a macro from an offset.
+    ///
+    /// The macro is created if it is the next one. It may only use macros before it and chords
+    /// that don't run it.
+    pub fn set_macro(
+        &mut self,
+        macro_: u8,
+        offset: usize,
+        actions: &[Action],
+    ) -> Result<(), Error> {
+        for &action in actions {
+            match action {
+                Action::Macro(x) if x >= macro_ => return Err(Error::InvalidMacro),
+                action => self.check(action)?,
+            }
+        }
+        self.check_cycle(actions, Action::Macro(macro_))?;
+        self.check_depth(actions, Action::Macro(macro_))?;
+        if macro_ as usize == self.macros.len() {
+            self.macros.push(Vec::new());
+        }
+        let macro_ = self.macros.get_mut(macro_ as usize).ok_or(Error::InvalidMacro)?;
+        replace(macro_, offset, actions).ok_or(Error::InvalidMacro)
+    }
+
+    fn check(&self, action: Action) -> Result<(), Error> {
+        match action {
+            Action::Chord(x) if x as usize >= self.chords.len() => Err(Error::InvalidChord),
+            Action::Macro(x) if x as usize >= self.macros.len() => Err(Error::InvalidMacro),
+            Action::Layer(x) | Action::ToggleLayer(x) if x as usize >= self.layers.len() => {
+                Err(Error::InvalidLayer)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Makes sure that none of the (existing) actions runs the target chord or macro.
+    ///
+    /// The keymap has no cycles, so the actions of the target itself are not relevant.
+    fn check_cycle(&self, actions: &[Action], target: Action) -> Result<(), Error> {
+        let mut chords = vec![false; self.chords.len()];
+        let mut macros = vec![false; self.macros.len()];
+        let mut todo = actions.to_vec();
+        while let Some(action) = todo.pop() {
+            if action == target {
+                return Err(Error::Cycle);
+            }
+            let (visited, actions) = match action {
+                Action::Chord(x) => (&mut chords[x as usize], &self.chords[x as usize]),
+                Action::Macro(x) => (&mut macros[x as usize], &self.macros[x as usize]),
+                _ => continue,
+            };
+            if !core::mem::replace(visited, true) {
+                todo.extend_from_slice(actions);
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes sure that no chord or macro nests too deep once the actions are added to the target.
+    ///
+    /// Depths are computed by iterating until they are stable (or too deep). The actions replaced
+    /// in the target are still counted, which is fine since they were not too deep.
+    fn check_depth(&self, actions: &[Action], target: Action) -> Result<(), Error> {
+        let target = match target {
+            Action::Chord(x) => (0, x as usize),
+            Action::Macro(x) => (1, x as usize),
+            _ => unreachable!(),
+        };
+        // The extra chord and macro stand for the target if it is created.
+        let mut depths = [vec![0; self.chords.len() + 1], vec![0; self.macros.len() + 1]];
+        let mut changed = true;
+        while core::mem::take(&mut changed) {
+            for (kind, lists) in [&self.chords, &self.macros].into_iter().enumerate() {
+                for i in 0 ..= lists.len() {
+                    let list = lists.get(i).map_or(&[][..], |x| &x[..]);
+                    let extra = if (kind, i) == target { actions } else { &[] };
+                    let depth = list.iter().chain(extra).map(|action| match *action {
+                        Action::Chord(x) => depths[0][x as usize] + 1,
+                        Action::Macro(x) => depths[1][x as usize] + 1,
+                        _ => 0,
+                    });
+                    let depth = depth.max().unwrap_or(0);
+                    if depth > MAX_DEPTH {
+                        return Err(Error::TooDeep);
+                    }
+                    changed |= core::mem::replace(&mut depths[kind][i], depth) != depth;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the action of word gaps.
+    ///
+    /// Word gaps are only detected if [`Config::word`](crate::Config::word) is set.
+    pub fn word(&self) -> Action {
+        self.word
+    }
+}
+
+impl Default for Keymap {
+    fn default() -> Keymap {
+        let mut builder = Builder { keymap: Keymap::from_table(&MAP).unwrap(), layer: 0 };
+        builder.map(NEXT_UNICODE_METHOD, Action::NextUnicodeMethod).unwrap();
+        builder.build()
+    }
+}
+
+/// Builds a keymap one sequence at a time.
+pub struct Builder {
+    keymap: Keymap,
+    /// Layer of the next mapped sequences.
+    layer: usize,
+}
+
+impl Builder {
+    /// Selects the layer of the next mapped sequences, creating it if needed.
+    ///
+    /// Layers are created in order, such that selecting a layer also creates the ones below.
+    pub fn layer(&mut self, layer: u8) -> &mut Builder {
+        self.layer = layer as usize;
+        if self.keymap.layers.len() <= self.layer {
+            self.keymap.layers.resize(self.layer + 1, vec![None; LENGTH]);
+        }
+        self
+    }
+
+    /// Maps a sequence (e.g. `".-"`) to an action in the current layer.
+    pub fn map(&mut self, sequence: &str, action: Action) -> Result<&mut Builder, Error> {
+        let sequence = parse(sequence)?;
+        self.check(action)?;
+        let actions = &mut self.keymap.layers[self.layer];
+        if actions[sequence].is_some() {
+            return Err(Error::Duplicate);
+        }
+        actions[sequence] = Some(action);
+        Ok(self)
+    }
+
+    /// Maps a sequence to a new chord.
+    ///
+    /// The chord may only use previously defined chords.
+    pub fn chord(&mut self, sequence: &str, actions: &[Action]) -> Result<&mut Builder, Error> {
+        for &action in actions {
+            self.check(action)?;
+        }
+        let chord = u8::try_from(self.keymap.chords.len()).map_err(|_| Error::TooManyChords)?;
+        self.keymap.chords.push(actions.to_vec());
+        if let Err(error) = self.map(sequence, Action::Chord(chord)) {
+            self.keymap.chords.pop();
+            return Err(error);
+        }
+        Ok(self)
+    }
+
+    /// Maps a sequence to a new macro.
+    ///
+    /// The macro may only use previously defined chords and macros.
+    pub fn macro_(&mut self, sequence: &str, actions: &[Action]) -> Result<&mut Builder, Error> {
+        for &action in actions {
+            self.check(action)?;
+        }
+        let macro_ = u8::try_from(self.keymap.macros.len()).map_err(|_| Error::TooManyMacros)?;
+        self.keymap.macros.push(actions.to_vec());
+        if let Err(error) = self.map(sequence, Action::Macro(macro_)) {
+            self.keymap.macros.pop();
+            return Err(error);
+        }
+        Ok(self)
+    }
+
+    /// Maps a sequence to a new macro typing an ASCII text.
+    pub fn text(&mut self, sequence: &str, text: &str) -> Result<&mut Builder, Error> {
+        self.macro_(sequence, &ascii::text(text)?)
+    }
+
+    /// Sets the action of word gaps (Space by default).
+    pub fn word(&mut self, action: Action) -> Result<&mut Builder, Error> {
+        self.check(action)?;
+        self.keymap.word = action;
+        Ok(self)
+    }
+
+    fn check(&self, action: Action) -> Result<(), Error> {
+        self.keymap.check(action)
+    }
+
+    pub fn build(&mut self) -> Keymap {
+        self.keymap.clone()
+    }
+}
+
+/// Replaces the actions of a list from an offset (at most its length).
+fn replace(list: &mut Vec<Action>, offset: usize, actions: &[Action]) -> Option<()> {
+    if offset > list.len() {
+        return None;
+    }
+    list.truncate(offset);
+    list.extend_from_slice(actions);
+    Some(())
+}
+
+/// Default action of word gaps.
+const SPACE: Action = Action::Tap(44);
+
+/// Sequence switching to the next Unicode input method in the default keymap.
+pub const NEXT_UNICODE_METHOD: &str = "..--.-";
+
+// TODO: Add the default mapping in the README too.
+/// Default keycode of each sequence.
+///
+/// Left modifiers have a single dash (e.g. `...-.` for LCtrl) and right modifiers are the same
+/// sequence with dots and dashes swapped (e.g. `---.-` for RCtrl). New modifiers must follow this
+/// rule and existing sequences must not move.
+pub const MAP: [u8; 127] = [
+    0,   //
+    8,   // . E
+    23,  // - T
+    12,  // .. I
+    4,   // .- A
+    17,  // -. N
+    16,  // -- M
+    22,  // ... S
+    24,  // ..- U
+    21,  // .-. R
+    26,  // .-- W
+    7,   // -.. D
+    14,  // -.- K
+    10,  // --. G
+    18,  // --- O
+    11,  // .... H
+    25,  // ...- V
+    9,   // ..-. F
+    44,  // ..-- Space
+    15,  // .-.. L
+    42,  // .-.- BSp
+    19,  // .--. P
+    13,  // .--- J
+    5,   // -... B
+    27,  // -..- X
+    6,   // -.-. C
+    28,  // -.-- Y
+    29,  // --.. Z
+    20,  // --.- Q
+    43,  // ---. Tab
+    40,  // ---- Enter
+    34,  // ..... 5
+    33,  // ....- 4
+    224, // ...-. LCtrl
+    32,  // ...-- 3
+    225, // ..-.. LShift
+    41,  // ..-.- Esc
+    45,  // ..--. -/_
+    31,  // ..--- 2
+    226, // .-... LAlt
+    46,  // .-..- =/+
+    47,  // .-.-. [/{
+    48,  // .-.-- ]/}
+    49,  // .--.. \/|
+    51,  // .--.- ;/:
+    52,  // .---. '/"
+    30,  // .---- 1
+    35,  // -.... 6
+    53,  // -...- `/~
+    54,  // -..-. ,/<
+    55,  // -..-- ./>
+    56,  // -.-.. //?
+    76,  // -.-.- Delete
+    101, // -.--. Applic (but actually Menu)
+    230, // -.--- RAlt
+    36,  // --... 7
+    0, 0, 229, // --.-- RShift
+    37,  // ---.. 8
+    228, // ---.- RCtrl
+    38,  // ----. 9
+    39,  // ----- 0
+    0, 79, // .....- Right
+    82, // ....-. Up
+    0, 81, // ...-.. Down
+    0, 0, 0, 80, // ..-... Left
+    0, 0, 0, 0, 0, 0, 0, 227, // .-.... LGui
+    77,  // .-...- End
+    75,  // .-..-. PgUp
+    0, 78, // .-.-.. PgDown
+    0, 0, 0, 74, // .--... Home
+    0, 0, 0, 0, 0, 0, 0, 0, 58, // -....- F1
+    59, // -...-. F2
+    60, // -...-- F3
+    61, // -..-.. F4
+    62, // -..-.- F5
+    63, // -..--. F6
+    64, // -..--- F7
+    65, // -.-... F8
+    66, // -.-..- F9
+    67, // -.-.-. F10
+    68, // -.-.-- F11
+    69, // -.--.. F12
+    0, 0, 231, // -.---- RGui
+    70,  // --.... PrtScr
+    72,  // --...- Pause
+    73,  // --..-. Insert
+    154, // --..-- SysRq
+    0, 0, 0, 0, 0, 57, // ---..- Caps Lock
+    83, // ---.-. Num Lock
+    71, // ---.-- Scroll Lock
+    0, 0, 0, 0,
+];
+
+#[test]
+fn keycodes() {
+    #[track_caller]
+    fn test(xs: &[u8], s: usize, q: Action) {
+        let mut r = 0;
+        for &x in xs {
+            match x {
+                b'.' => r = 2 * r + 1,
+                b'-' => r = 2 * r + 2,
+                _ => unreachable!(),
+            }
+        }
+        assert_eq!(r, s);
+        assert_eq!(Action::from(MAP[r]), q);
+    }
+    // Use https://www.win.tue.nl/~aeb/linux/kbd/scancodes-14.html to check the key.
+    use Action::Tap as Key;
+    test(b"", 0, Key(0));
+
+    // Official codes
+    test(b".-", 4, Key(4)); // A
+    test(b"-...", 23, Key(5)); // B
+    test(b"-.-.", 25, Key(6)); // C
+    test(b"-..", 11, Key(7)); // D
+    test(b".", 1, Key(8)); // E
+    test(b"..-.", 17, Key(9)); // F
+    test(b"--.", 13, Key(10)); // G
+    test(b"....", 15, Key(11)); // H
+    test(b"..", 3, Key(12)); // I
+    test(b".---", 22, Key(13)); // J
+    test(b"-.-", 12, Key(14)); // K
+    test(b".-..", 19, Key(15)); // L
+    test(b"--", 6, Key(16)); // M
+    test(b"-.", 5, Key(17)); // N
+    test(b"---", 14, Key(18)); // O
+    test(b".--.", 21, Key(19)); // P
+    test(b"--.-", 28, Key(20)); // Q
+    test(b".-.", 9, Key(21)); // R
+    test(b"...", 7, Key(22)); // S
+    test(b"-", 2, Key(23)); // T
+    test(b"..-", 8, Key(24)); // U
+    test(b"...-", 16, Key(25)); // V
+    test(b".--", 10, Key(26)); // W
+    test(b"-..-", 24, Key(27)); // X
+    test(b"-.--", 26, Key(28)); // Y
+    test(b"--..", 27, Key(29)); // Z
+    test(b".----", 46, Key(30)); // 1
+    test(b"..---", 38, Key(31)); // 2
+    test(b"...--", 34, Key(32)); // 3
+    test(b"....-", 32, Key(33)); // 4
+    test(b".....", 31, Key(34)); // 5
+    test(b"-....", 47, Key(35)); // 6
+    test(b"--...", 55, Key(36)); // 7
+    test(b"---..", 59, Key(37)); // 8
+    test(b"----.", 61, Key(38)); // 9
+    test(b"-----", 62, Key(39)); // 0
+
+    // Custom codes
+    test(b"..--", 18, Key(44)); // Space
+    test(b".-.-", 20, Key(42)); // BSp
+    test(b"---.", 29, Key(43)); // Tab
+    test(b"----", 30, Key(40)); // Enter
+    test(b"...-.", 33, Key(224)); // LCtrl
+    test(b"..-..", 35, Key(225)); // LShift
+    test(b".-...", 39, Key(226)); // LAlt
+    test(b".-....", 79, Key(227)); // LGui
+    test(b"---.-", 60, Key(228)); // RCtrl
+    test(b"--.--", 58, Key(229)); // RShift
+    test(b"-.---", 54, Key(230)); // RAlt
+    test(b"-.----", 110, Key(231)); // RGui
+    test(b"..-.-", 36, Key(41)); // Esc
+    test(b"..--.", 37, Key(45)); // -/_
+    test(b".-..-", 40, Key(46)); // =/+
+    test(b".-.-.", 41, Key(47)); // [/{
+    test(b".-.--", 42, Key(48)); // ]/}
+    test(b".--..", 43, Key(49)); // \/|
+    test(b".--.-", 44, Key(51)); // ;/:
+    test(b".---.", 45, Key(52)); // '/"
+    test(b"-...-", 48, Key(53)); // `/~
+    test(b"-..-.", 49, Key(54)); // ,/<
+    test(b"-..--", 50, Key(55)); // ./>
+    test(b"-.-..", 51, Key(56)); // //?
+    test(b"-.-.-", 52, Key(76)); // Delete
+    test(b"-.--.", 53, Key(101)); // Applic (but actually Menu)
+    test(b".....-", 64, Key(79)); // Right
+    test(b"....-.", 65, Key(82)); // Up
+    test(b"...-..", 67, Key(81)); // Down
+    test(b"..-...", 71, Key(80)); // Left
+    test(b".-...-", 80, Key(77)); // End
+    test(b".-..-.", 81, Key(75)); // PgUp
+    test(b".-.-..", 83, Key(78)); // PgDown
+    test(b".--...", 87, Key(74)); // Home
+    test(b"-....-", 96, Key(58)); // F1
+    test(b"-...-.", 97, Key(59)); // F2
+    test(b"-...--", 98, Key(60)); // F3
+    test(b"-..-..", 99, Key(61)); // F4
+    test(b"-..-.-", 100, Key(62)); // F5
+    test(b"-..--.", 101, Key(63)); // F6
+    test(b"-..---", 102, Key(64)); // F7
+    test(b"-.-...", 103, Key(65)); // F8
+    test(b"-.-..-", 104, Key(66)); // F9
+    test(b"-.-.-.", 105, Key(67)); // F10
+    test(b"-.-.--", 106, Key(68)); // F11
+    test(b"-.--..", 107, Key(69)); // F12
+    test(b"--....", 111, Key(70)); // PrtScr
+    test(b"--...-", 112, Key(72)); // Pause
+    test(b"--..-.", 113, Key(73)); // Insert
+    test(b"--..--", 114, Key(154)); // SysRq
+    test(b"---..-", 120, Key(57)); // Caps Lock
+    test(b"---.-.", 121, Key(83)); // Num Lock
+    test(b"---.--", 122, Key(71)); // Scroll Lock
+}
+
+#[test]
+fn builder() {
+    use Action::*;
+    let mut builder = Keymap::builder();
+    builder.map(".-", Tap(4)).unwrap().map("-...", Press(5)).unwrap();
+    assert_eq!(builder.map(".-", Tap(6)).err(), Some(Error::Duplicate));
+    assert_eq!(builder.map("", Tap(6)).err(), Some(Error::Reserved));
+    assert_eq!(builder.map("........", Tap(6)).err(), Some(Error::Reserved));
+    assert_eq!(builder.map(".x", Tap(6)).err(), Some(Error::InvalidSequence));
+    assert_eq!(builder.map(".", Chord(0)).err(), Some(Error::InvalidChord));
+    assert_eq!(builder.chord(".", &[Chord(0)]).err(), Some(Error::InvalidChord));
+    assert_eq!(builder.macro_(".", &[Macro(0)]).err(), Some(Error::InvalidMacro));
+    assert_eq!(builder.text(".", "\u{7f}").err(), Some(Error::InvalidCharacter));
+    builder.chord(".", &[Tap(224), Tap(6)]).unwrap();
+    builder.chord("..", &[Chord(0), Tap(7)]).unwrap();
+    assert_eq!(builder.map("-", Layer(1)).err(), Some(Error::InvalidLayer));
+    assert_eq!(builder.word(Macro(0)).err(), Some(Error::InvalidMacro));
+    assert_eq!(builder.build().word(), Tap(44));
+    builder.word(Chord(1)).unwrap();
+    builder.layer(2).map(".", Tap(8)).unwrap().layer(0).map("-", Layer(1)).unwrap();
+    let keymap = builder.build();
+    assert_eq!(keymap.get(0, 4), Some(Tap(4)));
+    assert_eq!(keymap.get(0, 23), Some(Press(5)));
+    assert_eq!(keymap.get(0, 1), Some(Chord(0)));
+    assert_eq!(keymap.get(0, 3), Some(Chord(1)));
+    assert_eq!(keymap.chord(1), [Chord(0), Tap(7)]);
+    assert_eq!(keymap.get(0, 2), Some(Layer(1)));
+    assert_eq!(keymap.get(0, 5), None);
+    assert_eq!(keymap.get(0, LENGTH), None);
+    assert_eq!(keymap.layers(), 3);
+    assert_eq!(keymap.get(1, 1), None);
+    assert_eq!(keymap.get(2, 1), Some(Tap(8)));
+    assert_eq!(keymap.get(3, 1), None);
+    assert_eq!(keymap.word(), Chord(1));
+}
+
+#[test]
+fn set() {
+    use Action::*;
+    let mut keymap = Keymap::builder().build();
+    keymap.set(0, 1, Some(Tap(8))).unwrap();
+    assert_eq!(keymap.set(0, 0, Some(Tap(8))), Err(Error::Reserved));
+    assert_eq!(keymap.set(2, 1, Some(Tap(8))), Err(Error::InvalidLayer));
+    assert_eq!(keymap.set(1, 1, Some(Layer(2))), Err(Error::InvalidLayer));
+    assert_eq!(keymap.layers(), 1);
+    keymap.set(1, 1, Some(Layer(0))).unwrap();
+    assert_eq!(keymap.layers(), 2);
+    keymap.set(0, 1, None).unwrap();
+    assert_eq!(keymap.get(0, 1), None);
+    assert_eq!(keymap.set_chord(0, 0, &[Chord(0)]), Err(Error::InvalidChord));
+    keymap.set_chord(0, 0, &[Tap(224), Tap(6)]).unwrap();
+    keymap.set_chord(1, 0, &[Chord(0)]).unwrap();
+    keymap.set_chord(0, 1, &[Tap(7), Tap(8)]).unwrap();
+    assert_eq!(keymap.chord(0), [Tap(224), Tap(7), Tap(8)]);
+    assert_eq!(keymap.set_chord(0, 4, &[]), Err(Error::InvalidChord));
+    assert_eq!(keymap.set_chord(3, 0, &[]), Err(Error::InvalidChord));
+    assert_eq!(keymap.set_macro(0, 0, &[Macro(0)]), Err(Error::InvalidMacro));
+    keymap.set_macro(0, 0, &[Chord(1)]).unwrap();
+    assert_eq!(keymap.macros(), 1);
+    assert_eq!(keymap.set_chord(0, 0, &[Macro(0)]), Err(Error::Cycle));
+    keymap.set_chord(2, 0, &[Macro(0)]).unwrap();
+    assert_eq!(keymap.set_macro(0, 1, &[Chord(2)]), Err(Error::Cycle));
+    assert_eq!(keymap.set_word(Macro(1)), Err(Error::InvalidMacro));
+    keymap.set_word(Macro(0)).unwrap();
+    assert_eq!(keymap.word(), Macro(0));
+}
+
+#[test]
+fn depth() {
+    use Action::*;
+    let mut keymap = Keymap::builder().build();
+    keymap.set_chord(0, 0, &[Tap(4)]).unwrap();
+    for chord in 1 ..= MAX_DEPTH as u8 {
+        keymap.set_chord(chord, 0, &[Tap(4), Chord(chord - 1)]).unwrap();
+    }
+    let next = MAX_DEPTH as u8 + 1;
+    assert_eq!(keymap.set_chord(next, 0, &[Chord(next - 1)]), Err(Error::TooDeep));
+    keymap.set_chord(next, 0, &[Chord(next - 2)]).unwrap();
+    // Nesting the first chord deeper also nests the chords running it deeper.
+    keymap.set_macro(0, 0, &[Tap(5)]).unwrap();
+    assert_eq!(keymap.set_chord(0, 1, &[Macro(0)]), Err(Error::TooDeep));
+    // Once the second chord no longer runs the first one, the first one may nest deeper.
+    keymap.set_chord(1, 1, &[Tap(5)]).unwrap();
+    keymap.set_chord(0, 0, &[Macro(0)]).unwrap();
+}
+
+#[test]
+fn from_table() {
+    assert!(Keymap::from_table(&[0, 8, 23]).is_ok());
+    assert_eq!(Keymap::from_table(&[8]).err(), Some(Error::Reserved));
+    assert_eq!(Keymap::from_table(&[0; LENGTH + 1]).err(), Some(Error::Reserved));
+    let next_unicode_method = parse(NEXT_UNICODE_METHOD).unwrap();
+    assert_eq!(Keymap::default().get(0, next_unicode_method), Some(Action::NextUnicodeMethod));
+}
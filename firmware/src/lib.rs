@@ -14,79 +14,266 @@
 
 #![no_std]
 
+extern crate alloc;
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
 use defmt::Format;
 
+pub use crate::adaptive::Adaptive;
+use crate::adaptive::Learner;
+pub use crate::debounce::Debounce;
+use crate::debounce::DebounceLayer;
+pub use crate::keymap::Keymap;
+pub use crate::time::{Clock, Duration, Instant};
+
+mod adaptive;
+pub mod ascii;
+pub mod console;
+pub mod consumer;
+mod debounce;
+pub mod keymap;
+pub mod protocol;
+pub mod store;
+mod time;
+pub mod unicode;
+pub mod vendor;
+
+#[derive(Clone, Copy)]
 pub struct Config {
-    /// Maximum timestamp (timestamps wrap back to 0 after this value).
-    pub maximum: usize,
+    /// Timer of the input timestamps.
+    pub clock: Clock,
+
+    /// Press duration after which a press is long.
+    pub long: Duration,
+
+    /// Press duration after which a press is cancelled.
+    pub cancel: Duration,
+
+    /// Release duration after which a letter ends.
+    pub letter: Duration,
+
+    /// Release duration after which a word ends (if word gaps are detected).
+    ///
+    /// The release duration is measured from the end of the last press. Word gaps run the word
+    /// action of the keymap (see [`Keymap::word()`]).
+    pub word: Option<Duration>,
+
+    /// Whether holding a press right after a letter repeats it (see [`Repeat`]).
+    pub repeat: Option<Repeat>,
+
+    /// Whether durations adapt to the user.
+    ///
+    /// The bounds apply to the long press duration. Other durations are scaled accordingly.
+    pub adaptive: Option<Adaptive>,
 
-    /// Time period after which the state may step without interaction.
-    pub period: usize,
+    /// How the button is debounced.
+    pub debounce: Debounce,
+
+    /// What to do when inputs lag.
+    pub lag: Lag,
+
+    /// How tapped modifiers behave (outside macros and code points, where they are one-shot).
+    pub modifier: ModifierMode,
 }
 
 impl Config {
-    /// Returns the difference between 2 timestamps.
-    fn diff(&self, reference: usize, current: usize) -> usize {
-        if current < reference {
-            self.maximum - reference + current + 1
-        } else {
-            current - reference
+    /// Returns whether the durations are consistent.
+    ///
+    /// Durations are positive, a press is long before being cancelled, the word gap is longer than
+    /// the letter gap, and the repeat delay is between the long and cancel durations.
+    pub fn is_valid(&self) -> bool {
+        let zero = Duration::from_millis(0);
+        let mut valid = zero < self.long && self.long < self.cancel && zero < self.letter;
+        if let Some(word) = self.word {
+            valid &= self.letter < word;
+        }
+        if let Some(Repeat { window, delay, interval }) = self.repeat {
+            valid &= zero < window && self.long < delay && delay < self.cancel && zero < interval;
         }
+        valid
+    }
+}
+
+/// Durations of the repeat gesture.
+///
+/// A press starting shortly after the end of a letter and held long enough repeats the letter (if
+/// it is a key, chord, macro, Unicode character, consumer usage, or mouse action) until released.
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Repeat<T = Duration> {
+    /// Release duration after the end of a letter within which a press may repeat.
+    pub window: T,
+
+    /// Press duration after which the press repeats (between the long and cancel durations).
+    pub delay: T,
+
+    /// Duration between repetitions.
+    pub interval: T,
+}
+
+impl<T> Repeat<T> {
+    fn map<U>(self, mut f: impl FnMut(T) -> U) -> Repeat<U> {
+        Repeat { window: f(self.window), delay: f(self.delay), interval: f(self.interval) }
     }
 }
 
+/// Policy when inputs lag.
+///
+/// Inputs lag when 2 consecutive timestamps are further apart than a quarter of the long press
+/// duration (e.g. because USB stalled). The lag is reported as [`Event::Lag`] regardless of the
+/// policy. Inputs don't lag when ready, such that they don't need to be stepped while idle.
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lag {
+    /// The lag counts as if the button state did not change.
+    ///
+    /// A short press may become long or cancelled and a letter may end.
+    Ignore,
+
+    /// The lag counts as a quarter of the long press duration.
+    Clamp,
+
+    /// The current letter is dropped and a press in progress starts over.
+    Reset,
+}
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModifierMode {
+    /// Tapped modifiers apply to the next tapped key.
+    OneShot,
+
+    /// Like one-shot, but tapping a modifier twice locks it until tapped again.
+    Locked,
+
+    /// Tapped modifiers apply to the given number of next tapped keys.
+    ///
+    /// Tapping a modifier again restarts the count.
+    Held(u8),
+}
+
 #[derive(Format, Clone, Copy)]
 pub struct Input {
     /// The current timestamp.
-    pub timestamp: usize,
+    pub timestamp: Instant,
     /// Whether the button is being pressed.
-    // TODO: Add a layer for debouncing? See https://github.com/TyberiusPrime/debouncing
     pub button: bool,
 }
 
-#[derive(Format, Clone, Copy)]
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
 enum Bit {
     Zero,
     One,
     End,
+    Word,
+    Repeat,
+    /// Drops the current letter (without cancelling).
+    Reset,
     Cancel,
 }
 
-#[derive(Format, Clone, Copy)]
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BitState {
     Ready,
     Short,
     Long,
     Cancel,
     Done,
+    /// The letter ended and the word may end (or the letter may repeat).
+    Gap,
+    /// The last letter is repeating.
+    Repeat,
 }
 
 struct BitLayer {
     config: Config,
     state: BitState,
+    /// Current long press duration in ticks (see [`Config::long`]).
+    long: usize,
+    /// Current cancel press duration in ticks (see [`Config::cancel`]).
+    cancel: usize,
+    /// Current letter release duration in ticks (see [`Config::letter`]).
+    letter: usize,
+    /// Current word release duration in ticks (see [`Config::word`]).
+    word: Option<usize>,
+    /// Current repeat durations in ticks (see [`Config::repeat`]).
+    repeat: Option<Repeat<usize>>,
+    /// Whether the current press started within the repeat window.
+    armed: bool,
+    /// Learns the long press duration if adaptive.
+    learner: Option<Learner>,
+    /// Lag of the last step in ticks, if any.
+    lag: Option<usize>,
     /// Timestamp of the last state change.
-    reference: usize,
-    /// Previous timestamp.
-    previous: usize,
+    reference: Instant,
+    /// Timestamp of the last press.
+    pressed: Instant,
+    /// Previous timestamp (none before the first step).
+    previous: Option<Instant>,
 }
 
 impl BitLayer {
     fn new(config: Config) -> BitLayer {
-        BitLayer { config, state: BitState::Ready, reference: 0, previous: 0 }
+        let ticks = |duration| config.clock.ticks(duration);
+        let learner = |x: Adaptive| Learner::new(ticks(x.minimum), ticks(x.maximum));
+        BitLayer {
+            config,
+            state: BitState::Ready,
+            long: ticks(config.long),
+            cancel: ticks(config.cancel),
+            letter: ticks(config.letter),
+            word: config.word.map(ticks),
+            repeat: config.repeat.map(|x| x.map(ticks)),
+            armed: false,
+            learner: config.adaptive.map(learner),
+            lag: None,
+            reference: Instant::default(),
+            pressed: Instant::default(),
+            previous: None,
+        }
     }
 
     fn step(&mut self, input: Input) -> Option<Bit> {
-        if self.config.diff(self.previous, input.timestamp) > self.config.period / 4 {
-            defmt::warn!(
-                "Lag detected {} << {} ({})",
-                self.previous,
-                input.timestamp,
-                self.config.period
-            );
-        }
-        self.previous = input.timestamp;
-        let timeout = self.config.diff(self.reference, input.timestamp) > self.config.period;
+        let clock = self.config.clock;
+        let threshold = self.long / 4;
+        let previous = self.previous.replace(input.timestamp);
+        let lag = previous.map_or(0, |previous| clock.elapsed(previous, input.timestamp));
+        self.lag = None;
         use BitState::*;
+        if lag > threshold && self.state != Ready {
+            defmt::warn!("Lag detected {} ({:?})", lag, self.config.lag);
+            self.lag = Some(lag);
+            match self.config.lag {
+                Lag::Ignore => (),
+                Lag::Clamp => {
+                    self.reference = clock.add(self.reference, lag - threshold);
+                    self.pressed = clock.add(self.pressed, lag - threshold);
+                }
+                Lag::Reset => {
+                    let letter = matches!(self.state, Short | Long | Cancel | Done);
+                    self.state = Ready;
+                    self.reference = input.timestamp;
+                    return letter.then_some(Bit::Reset);
+                }
+            }
+        }
+        let elapsed = clock.elapsed(self.reference, input.timestamp);
+        let timeout = match self.state {
+            Ready | Cancel => false,
+            Short => elapsed > self.long,
+            Long => {
+                let limit = match self.repeat {
+                    Some(repeat) if self.armed => repeat.delay,
+                    _ => self.cancel,
+                };
+                clock.elapsed(self.pressed, input.timestamp) > limit
+            }
+            Done => elapsed > self.letter,
+            Gap => elapsed > self.gap(),
+            Repeat => elapsed > self.repeat.map_or(0, |repeat| repeat.interval),
+        };
+        // The reference is not reset at the end of a letter, such that the word gap and repeat
+        // window are measured from the last release.
+        let end = if self.word.is_some() || self.repeat.is_some() { Gap } else { Ready };
+        let word = self.word.map(|_| Bit::Word);
         let (state, reset, bit) = match (self.state, input.button, timeout) {
             (Ready, false, _) => (Ready, true, None),
             (Ready, true, _) => (Short, true, None),
@@ -95,170 +282,242 @@ impl BitLayer {
             (Short, true, true) => (Long, true, None),
             (Long, false, _) => (Done, true, Some(Bit::One)),
             (Long, true, false) => (Long, false, None),
+            (Long, true, true) if self.armed => (Repeat, true, Some(Bit::Repeat)),
             (Long, true, true) => (Cancel, true, None),
             (Cancel, false, _) => (Ready, true, Some(Bit::Cancel)),
             (Cancel, true, _) => (Cancel, true, None),
             (Done, false, false) => (Done, false, None),
-            (Done, false, true) => (Ready, true, Some(Bit::End)),
+            (Done, false, true) => (end, false, Some(Bit::End)),
             (Done, true, _) => (Short, true, None),
+            (Gap, false, false) => (Gap, false, None),
+            (Gap, false, true) => (Ready, true, word),
+            (Gap, true, _) => (Short, true, None),
+            (Repeat, false, _) => (Ready, true, None),
+            (Repeat, true, false) => (Repeat, false, None),
+            (Repeat, true, true) => (Repeat, true, Some(Bit::Repeat)),
         };
+        if let (Ready | Done | Gap, Short) = (self.state, state) {
+            self.pressed = input.timestamp;
+            let window = self.repeat.map(|repeat| self.letter + repeat.window);
+            self.armed = self.state == Gap && window.is_some_and(|window| elapsed <= window);
+        }
+        if let (Some(learner), Some(Bit::Zero | Bit::One)) = (&mut self.learner, bit) {
+            let duration = clock.elapsed(self.pressed, input.timestamp);
+            let long = learner.record(duration, matches!(bit, Some(Bit::One)));
+            self.adapt(long);
+        }
         self.state = state;
         if reset {
             self.reference = input.timestamp;
         }
         bit
     }
+
+    /// Sets the long press duration and scales the other durations accordingly.
+    fn adapt(&mut self, long: usize) {
+        let clock = self.config.clock;
+        let base = clock.ticks(self.config.long);
+        let scale = |x| (clock.ticks(x) as u64 * long as u64 / base as u64) as usize;
+        self.long = long;
+        self.cancel = scale(self.config.cancel);
+        self.letter = scale(self.config.letter);
+        self.word = self.config.word.map(scale);
+        self.repeat = self.config.repeat.map(|repeat| Repeat {
+            window: scale(repeat.window),
+            delay: scale(repeat.delay),
+            interval: clock.ticks(repeat.interval),
+        });
+        defmt::debug!("Durations {} {} {} {:?}", self.long, self.cancel, self.letter, self.word);
+    }
+
+    /// Returns the release duration after which the gap ends.
+    fn gap(&self) -> usize {
+        match (self.word, self.repeat) {
+            (Some(word), _) => word,
+            (None, Some(repeat)) => self.letter + repeat.window,
+            (None, None) => 0,
+        }
+    }
+}
+
+/// Inverts the case of a letter by toggling shift.
+///
+/// Letters are only inverted without other modifiers, such that shortcuts are preserved.
+fn invert_case(modifiers: &mut u8) {
+    const SHIFTS: u8 = 0x22;
+    match *modifiers {
+        0 => *modifiers = 0x02,
+        x if x & !SHIFTS == 0 => *modifiers = 0,
+        _ => (),
+    }
+}
+
+/// Returns the modifier bit of a keycode, if it is a modifier.
+fn modifier(key: u8) -> Option<u8> {
+    match key {
+        224 ..= 231 => Some(1 << (key - 224)),
+        _ => None,
+    }
 }
 
 #[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
-enum Seq {
-    Modifier(u8),
-    Key(u8),
-    Invalid,
+pub enum Action {
+    /// Presses and releases a key.
+    ///
+    /// Tapping a modifier applies it to the next tapped key only. Within a sequence, this toggles
+    /// the key instead.
+    Tap(u8),
+
+    /// Presses a key until it is released.
+    Press(u8),
+
+    /// Presses and releases a consumer control usage (e.g. [`consumer::VOLUME_UP`]).
+    ///
+    /// Consumer control is independent of keys, modifiers, and sequences.
+    Consumer(u16),
+
+    /// Moves the mouse pointer (right and down for positive values).
+    ///
+    /// Repeated moves accelerate (see [`Config::repeat`]).
+    Move(i8, i8),
+
+    /// Scrolls the mouse wheel horizontally and vertically (right and up for positive values).
+    ///
+    /// Repeated scrolls accelerate (see [`Config::repeat`]).
+    Scroll(i8, i8),
+
+    /// Presses and releases mouse buttons (bit 0 is left, bit 1 is right, and bit 2 is middle).
+    ///
+    /// Like consumer control, the mouse is independent of keys, modifiers, and sequences.
+    Click(u8),
+
+    /// Presses mouse buttons if released and releases them otherwise (e.g. to drag).
+    Drag(u8),
+
+    /// Releases a key.
+    Release(u8),
+
+    /// Presses a key if released and releases it otherwise.
+    Toggle(u8),
+
+    /// Starts a sequence.
+    ///
+    /// Keys are toggled until the end of the sequence.
+    PrepareSequence,
+
+    /// Ends a sequence.
+    ///
+    /// Keys that are still pressed are released.
+    CommitSequence,
+
+    /// Runs the actions of a chord (see [`Keymap::chord()`]) within a sequence.
+    Chord(u8),
+
+    /// Runs the actions of a macro (see [`Keymap::macro_()`]) outside sequences.
+    Macro(u8),
+
+    /// Types a code point using the current Unicode input method.
+    Unicode(char),
+
+    /// Switches to the next Unicode input method.
+    NextUnicodeMethod,
+
+    /// Switches to a layer for the next sequence only.
+    Layer(u8),
+
+    /// Switches to a layer until toggled again.
+    ///
+    /// Toggled layers form a stack. Untoggling a layer removes it from the stack.
+    ToggleLayer(u8),
+
+    /// Releases all keys and forgets about pending modifiers and sequence.
     Cancel,
 }
 
-impl From<u8> for Seq {
-    fn from(x: u8) -> Seq {
-        use Seq::*;
-        match x {
-            224 ..= 231 => Modifier(1 << (x - 224)),
-            _ => Key(x),
-        }
+impl From<u8> for Action {
+    fn from(x: u8) -> Action {
+        Action::Tap(x)
     }
 }
 
-// TODO: Those are actually actions. Most of them are just key press+release. But we can add a
-// PrepareSequence and CommitSequence actions. Keys in between are toggled. All keys are released
-// automatically (if not already) during at the end of the sequence.
-
-// TODO: Allow pre-configured sequence of keys (e.g. to output unicode with Ctrl+Shift+U xxx). See
-// https://github.com/TyberiusPrime/KeyToKey/blob/91ba3fe917e626c820f681fd2e2a97637ef16344/src/lib.rs#L313-L322
-
-// TODO: Add the default mapping in the README too.
-/// Maps sequences to keycodes.
-const MAP: [u8; 127] = [
-    0,   //
-    8,   // . E
-    23,  // - T
-    12,  // .. I
-    4,   // .- A
-    17,  // -. N
-    16,  // -- M
-    22,  // ... S
-    24,  // ..- U
-    21,  // .-. R
-    26,  // .-- W
-    7,   // -.. D
-    14,  // -.- K
-    10,  // --. G
-    18,  // --- O
-    11,  // .... H
-    25,  // ...- V
-    9,   // ..-. F
-    44,  // ..-- Space
-    15,  // .-.. L
-    42,  // .-.- BSp
-    19,  // .--. P
-    13,  // .--- J
-    5,   // -... B
-    27,  // -..- X
-    6,   // -.-. C
-    28,  // -.-- Y
-    29,  // --.. Z
-    20,  // --.- Q
-    43,  // ---. Tab
-    40,  // ---- Enter
-    34,  // ..... 5
-    33,  // ....- 4
-    224, // ...-. LCtrl
-    32,  // ...-- 3
-    225, // ..-.. LShift
-    41,  // ..-.- Esc
-    45,  // ..--. -/_
-    31,  // ..--- 2
-    226, // .-... LAlt
-    // TODO: We also need RCtrl, RShift, and RAlt (used for compose)
-    46,  // .-..- =/+
-    47,  // .-.-. [/{
-    48,  // .-.-- ]/}
-    49,  // .--.. \/|
-    51,  // .--.- ;/:
-    52,  // .---. '/"
-    30,  // .---- 1
-    35,  // -.... 6
-    53,  // -...- `/~
-    54,  // -..-. ,/<
-    55,  // -..-- ./>
-    56,  // -.-.. //?
-    76,  // -.-.- Delete
-    101, // -.--. Applic (but actually Menu)
-    0, 36, // --... 7
-    0, 0, 0, 37, // ---.. 8
-    0, 38, // ----. 9
-    39, // ----- 0
-    0, 79, // .....- Right
-    82, // ....-. Up
-    0, 81, // ...-.. Down
-    0, 0, 0, 80, // ..-... Left
-    0, 0, 0, 0, 0, 0, 0, 0, 77, // .-...- End
-    75, // .-..-. PgUp
-    0, 78, // .-.-.. PgDown
-    0, 0, 0, 74, // .--... Home
-    0, 0, 0, 0, 0, 0, 0, 0, 58, // -....- F1
-    59, // -...-. F2
-    60, // -...-- F3
-    61, // -..-.. F4
-    62, // -..-.- F5
-    63, // -..--. F6
-    64, // -..--- F7
-    65, // -.-... F8
-    66, // -.-..- F9
-    67, // -.-.-. F10
-    68, // -.-.-- F11
-    69, // -.--.. F12
-    0, 0, 0, 70,  // --.... PrtScr
-    72,  // --...- Pause
-    73,  // --..-. Insert
-    154, // --..-- SysRq
-    0, 0, 0, 0, 0, 57, // ---..- Caps Lock
-    83, // ---.-. Num Lock
-    71, // ---.-- Scroll Lock
-    0, 0, 0, 0,
-];
-
 struct SeqLayer {
-    state: usize, // < MAP.len()
+    keymap: Keymap,
+    /// Toggled layers (the last one is on top).
+    layers: Vec<u8>,
+    /// Layer for the next sequence only.
+    momentary: Option<u8>,
+    /// Action of the last sequence, for repetition.
+    last: Option<Action>,
+    state: usize, // < 511
 }
 
 impl SeqLayer {
-    fn new() -> SeqLayer {
-        SeqLayer { state: 0 }
+    fn new(keymap: Keymap) -> SeqLayer {
+        SeqLayer { keymap, layers: Vec::new(), momentary: None, last: None, state: 0 }
+    }
+
+    /// Returns the current layer.
+    fn layer(&self) -> u8 {
+        self.momentary.or_else(|| self.layers.last().cloned()).unwrap_or(0)
     }
 
-    fn step(&mut self, input: Bit) -> Option<Seq> {
+    /// Returns the action of a sequence in the current layer, falling back to lower layers.
+    fn get(&self, sequence: usize) -> Option<Action> {
+        let mut layers = self.momentary.iter().chain(self.layers.iter().rev()).chain(&[0]);
+        layers.find_map(|&layer| self.keymap.get(layer, sequence))
+    }
+
+    fn step(&mut self, input: Bit) -> Option<Action> {
         use Bit::*;
         let bit = match input {
             Zero => 0,
             One => 1,
-            End if self.state < MAP.len() && MAP[self.state] > 0 => {
-                let seq = MAP[self.state].into();
+            End if self.get(self.state).is_some() => {
+                let action = self.get(self.state).unwrap();
                 self.state = 0;
-                return Some(seq);
+                self.momentary = None;
+                self.last = Some(action);
+                return Some(action);
             }
-            End if self.state < 255 => {
+            End if self.state < keymap::LENGTH => {
                 defmt::warn!("Reserved sequence {:#b}", self.state);
                 self.state = 0;
-                return Some(Seq::Invalid);
+                self.momentary = None;
+                self.last = None;
+                return Some(Action::Cancel);
             }
             End => {
-                let seq = (self.state - 255) as u8;
-                defmt::info!("Low-level sequence {:#b} {}", self.state, seq);
+                let key = (self.state - keymap::LENGTH) as u8;
+                defmt::info!("Low-level sequence {:#b} {}", self.state, key);
+                self.state = 0;
+                self.momentary = None;
+                self.last = Some(key.into());
+                return Some(key.into());
+            }
+            Word => return Some(self.keymap.word()),
+            Reset => {
                 self.state = 0;
-                return Some(seq.into());
+                return None;
+            }
+            Repeat => {
+                use Action::{Chord, Click, Consumer, Macro, Move, Scroll, Tap, Unicode};
+                return self.last.filter(|x| {
+                    matches!(
+                        x,
+                        Tap(_)
+                            | Consumer(_)
+                            | Move(..)
+                            | Scroll(..)
+                            | Click(_)
+                            | Chord(_)
+                            | Macro(_)
+                            | Unicode(_)
+                    )
+                });
             }
             Cancel if self.state == 0 => {
-                return Some(Seq::Cancel);
+                self.last = None;
+                return Some(Action::Cancel);
             }
             Cancel => {
                 self.state = 0;
@@ -275,42 +534,446 @@ impl SeqLayer {
     }
 }
 
-// TODO: Make this a sequence of key presses and releases. Unreleased keys are released at the end
-// of the sequence. A maximum of 6 non-modifiers can be pressed at the same time.
-#[derive(Default, Clone, Copy)]
+/// Event returned by [`State::step()`].
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// A keyboard report to send.
+    Keyboard(Output),
+
+    /// A consumer control report to send (the pressed usage or 0 if none).
+    Consumer(u16),
+
+    /// A mouse report to send.
+    Mouse(Mouse),
+
+    /// Inputs lagged for this duration (see [`Lag`]).
+    Lag(Duration),
+}
+
+/// Keyboard LEDs (as in the HID boot keyboard output report).
+#[derive(Format, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HostLeds {
+    pub num_lock: bool,
+    pub caps_lock: bool,
+    pub scroll_lock: bool,
+    pub compose: bool,
+    pub kana: bool,
+}
+
+impl HostLeds {
+    /// Parses a raw output report.
+    pub fn parse(report: &[u8]) -> Option<HostLeds> {
+        let &[bits] = report else { return None };
+        let bit = |i: u8| bits & 1 << i != 0;
+        Some(HostLeds {
+            num_lock: bit(0),
+            caps_lock: bit(1),
+            scroll_lock: bit(2),
+            compose: bit(3),
+            kana: bit(4),
+        })
+    }
+}
+
+/// Keyboard report.
+#[derive(Format, Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub struct Output {
     pub modifiers: u8,
-    pub key: u8,
+    /// Pressed keys in press order (unused entries are 0 and at the end).
+    pub keys: [u8; 6],
+}
+
+impl Output {
+    /// Returns the raw report (as in the HID boot keyboard report).
+    pub fn report(&self) -> [u8; 8] {
+        let mut report = [0; 8];
+        report[0] = self.modifiers;
+        report[2 ..].copy_from_slice(&self.keys);
+        report
+    }
+
+    fn is_pressed(&self, key: u8) -> bool {
+        match modifier(key) {
+            Some(m) => self.modifiers & m != 0,
+            None => self.keys.contains(&key),
+        }
+    }
+
+    fn press(&mut self, key: u8) {
+        if self.is_pressed(key) {
+            return;
+        }
+        match modifier(key) {
+            Some(m) => self.modifiers |= m,
+            None => match self.keys.iter_mut().find(|x| **x == 0) {
+                Some(x) => *x = key,
+                None => defmt::warn!("Too many keys pressed. Dropping {}.", key),
+            },
+        }
+    }
+
+    fn release(&mut self, key: u8) {
+        match modifier(key) {
+            Some(m) => self.modifiers &= !m,
+            None => {
+                if let Some(i) = self.keys.iter().position(|&x| x == key) {
+                    self.keys.copy_within(i + 1 .., i);
+                    self.keys[5] = 0;
+                }
+            }
+        }
+    }
+}
+
+/// Mouse report.
+#[derive(Format, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Mouse {
+    /// Pressed buttons (bit 0 is left, bit 1 is right, and bit 2 is middle).
+    pub buttons: u8,
+    pub x: i8,
+    pub y: i8,
+    pub wheel: i8,
+    pub pan: i8,
+}
+
+impl Mouse {
+    /// Returns the raw report (as in the HID boot mouse report followed by wheel and pan).
+    pub fn report(&self) -> [u8; 5] {
+        [self.buttons, self.x as u8, self.y as u8, self.wheel as u8, self.pan as u8]
+    }
+}
+
+/// Usage statistics since the state was created.
+#[derive(Format, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// Number of short and long presses.
+    pub presses: u32,
+
+    /// Number of actions run (including repetitions and word gaps).
+    pub actions: u32,
+
+    /// Number of cancellations.
+    pub cancels: u32,
+
+    /// Longest input lag.
+    pub max_lag: Duration,
 }
 
+/// Maximum speed factor of repeated mouse moves and scrolls.
+const MAX_SPEED: i8 = 8;
+
 pub struct State {
+    debounce: DebounceLayer,
     bit: BitLayer,
     seq: SeqLayer,
+    /// Keys currently pressed.
     out: Output,
+    /// Last queued report.
+    sent: Output,
+    /// How tapped modifiers behave.
+    modifier: ModifierMode,
+    /// Number of next tapped keys each tapped modifier applies to, indexed by modifier bit.
+    latched: [u8; 8],
+    /// Modifiers applied to all tapped keys.
+    locked: u8,
+    /// Whether a sequence is in progress.
+    sequence: bool,
+    /// How code points are typed.
+    unicode: unicode::Method,
+    /// Whether a macro or code point is being typed.
+    typing: bool,
+    /// Keyboard LEDs set by the host.
+    host_leds: HostLeds,
+    /// Pressed mouse buttons.
+    buttons: u8,
+    /// Whether the current action is a repetition.
+    repeating: bool,
+    /// Speed factor of the last mouse move or scroll.
+    speed: i8,
+    stats: Stats,
+    /// Events to return.
+    queue: VecDeque<Event>,
 }
 
 impl State {
     pub fn new(config: Config) -> State {
+        let debounce = DebounceLayer::new(config);
         let bit = BitLayer::new(config);
-        let seq = SeqLayer::new();
-        State { bit, seq, out: Output::default() }
-    }
-
-    pub fn step(&mut self, input: Input) -> Option<Output> {
-        let bit = self.bit.step(input)?;
-        let seq = self.seq.step(bit)?;
-        defmt::trace!("{:?}", seq);
-        match seq {
-            Seq::Modifier(x) => self.out.modifiers |= x,
-            Seq::Key(x) => {
-                self.out.key = x;
-                let out = self.out;
-                self.out = Output::default();
-                return Some(out);
+        let seq = SeqLayer::new(Keymap::default());
+        let out = Output::default();
+        let queue = VecDeque::new();
+        let unicode = unicode::Method::default();
+        State {
+            debounce,
+            bit,
+            seq,
+            out,
+            sent: out,
+            modifier: config.modifier,
+            latched: [0; 8],
+            locked: 0,
+            sequence: false,
+            unicode,
+            typing: false,
+            host_leds: HostLeds::default(),
+            buttons: 0,
+            repeating: false,
+            speed: 1,
+            stats: Stats::default(),
+            queue,
+        }
+    }
+
+    /// Steps the state and returns the events (e.g. reports to send), in order.
+    ///
+    /// Only report changes are returned. All the reports should be sent before the next step.
+    pub fn step(&mut self, input: Input) -> impl Iterator<Item = Event> + '_ {
+        let input = self.debounce.step(input);
+        let bit = self.bit.step(input);
+        if let Some(lag) = self.bit.lag {
+            let lag = self.bit.config.clock.duration(lag);
+            self.stats.max_lag = core::cmp::max(self.stats.max_lag, lag);
+            self.queue.push_back(Event::Lag(lag));
+        }
+        if let Some(bit) = bit {
+            self.repeating = bit == Bit::Repeat;
+            match bit {
+                Bit::Zero | Bit::One => self.stats.presses += 1,
+                Bit::Cancel => self.stats.cancels += 1,
+                _ => (),
             }
-            Seq::Invalid | Seq::Cancel => self.out = Output::default(),
-        };
-        None
+        }
+        if let Some(action) = bit.and_then(|bit| self.seq.step(bit)) {
+            self.stats.actions += 1;
+            self.apply(action);
+        }
+        self.queue.drain(..)
+    }
+
+    fn apply(&mut self, action: Action) {
+        defmt::trace!("{:?}", action);
+        match action {
+            Action::Tap(x) if self.sequence => self.apply(Action::Toggle(x)),
+            Action::Tap(x) => match modifier(x) {
+                Some(m) => self.latch(m),
+                None => {
+                    let mut tap = self.out;
+                    // Release the key first if it is held, such that the tap is visible.
+                    tap.release(x);
+                    self.send(tap);
+                    tap.modifiers |= self.latched_modifiers();
+                    if self.typing && self.host_leds.caps_lock && (4 ..= 29).contains(&x) {
+                        invert_case(&mut tap.modifiers);
+                    }
+                    tap.press(x);
+                    self.send(tap);
+                    self.send(self.out);
+                    self.latched.iter_mut().for_each(|x| *x = x.saturating_sub(1));
+                }
+            },
+            Action::Press(x) => {
+                self.out.press(x);
+                self.send(self.out);
+            }
+            Action::Release(x) => {
+                self.out.release(x);
+                self.send(self.out);
+            }
+            Action::Consumer(x) => {
+                self.queue.push_back(Event::Consumer(x));
+                self.queue.push_back(Event::Consumer(0));
+            }
+            Action::Move(x, y) => {
+                let speed = self.accelerate();
+                let (x, y) = (x.saturating_mul(speed), y.saturating_mul(speed));
+                self.send_mouse(Mouse { buttons: self.buttons, x, y, ..Mouse::default() });
+            }
+            Action::Scroll(pan, wheel) => {
+                let speed = self.accelerate();
+                let (pan, wheel) = (pan.saturating_mul(speed), wheel.saturating_mul(speed));
+                self.send_mouse(Mouse { buttons: self.buttons, wheel, pan, ..Mouse::default() });
+            }
+            Action::Click(x) => {
+                self.send_mouse(Mouse { buttons: self.buttons | x, ..Mouse::default() });
+                self.send_mouse(Mouse { buttons: self.buttons, ..Mouse::default() });
+            }
+            Action::Drag(x) => {
+                self.buttons ^= x;
+                self.send_mouse(Mouse { buttons: self.buttons, ..Mouse::default() });
+            }
+            Action::Toggle(x) if self.out.is_pressed(x) => self.apply(Action::Release(x)),
+            Action::Toggle(x) => self.apply(Action::Press(x)),
+            Action::PrepareSequence => self.sequence = true,
+            Action::CommitSequence => {
+                self.sequence = false;
+                self.release_all();
+            }
+            Action::Chord(x) => {
+                let nested = core::mem::replace(&mut self.sequence, true);
+                for i in 0 .. self.seq.keymap.chord(x).len() {
+                    self.apply(self.seq.keymap.chord(x)[i]);
+                }
+                if !nested {
+                    self.apply(Action::CommitSequence);
+                }
+            }
+            Action::Macro(x) => {
+                let sequence = core::mem::take(&mut self.sequence);
+                let typing = core::mem::replace(&mut self.typing, true);
+                for i in 0 .. self.seq.keymap.macro_(x).len() {
+                    self.apply(self.seq.keymap.macro_(x)[i]);
+                }
+                self.typing = typing;
+                self.sequence = sequence;
+            }
+            Action::Unicode(x) => {
+                let sequence = core::mem::take(&mut self.sequence);
+                let typing = core::mem::replace(&mut self.typing, true);
+                for action in unicode::actions(self.unicode, x) {
+                    self.apply(action);
+                }
+                self.typing = typing;
+                self.sequence = sequence;
+            }
+            Action::NextUnicodeMethod => {
+                self.unicode = self.unicode.next();
+                defmt::info!("Unicode input method {:?}", self.unicode);
+            }
+            Action::Layer(x) => self.seq.momentary = Some(x),
+            Action::ToggleLayer(x) => match self.seq.layers.iter().position(|&y| y == x) {
+                Some(i) => {
+                    self.seq.layers.remove(i);
+                }
+                None => self.seq.layers.push(x),
+            },
+            Action::Cancel => {
+                self.seq.momentary = None;
+                self.latched = [0; 8];
+                self.locked = 0;
+                self.sequence = false;
+                self.release_all();
+                if self.buttons != 0 {
+                    self.buttons = 0;
+                    self.send_mouse(Mouse::default());
+                }
+            }
+        }
+    }
+
+    /// Returns the speed factor of a mouse move or scroll.
+    ///
+    /// The speed increases with each repetition.
+    fn accelerate(&mut self) -> i8 {
+        self.speed = if self.repeating { core::cmp::min(self.speed + 1, MAX_SPEED) } else { 1 };
+        self.speed
+    }
+
+    fn send_mouse(&mut self, report: Mouse) {
+        self.queue.push_back(Event::Mouse(report));
+    }
+
+    /// Latches a tapped modifier.
+    ///
+    /// Modifiers tapped while typing are one-shot, such that text is typed the same in all modes.
+    fn latch(&mut self, m: u8) {
+        let i = m.trailing_zeros() as usize;
+        let mode = if self.typing { ModifierMode::OneShot } else { self.modifier };
+        match mode {
+            ModifierMode::OneShot => self.latched[i] = 1,
+            ModifierMode::Locked if self.locked & m != 0 => self.locked &= !m,
+            ModifierMode::Locked if self.latched[i] > 0 => {
+                self.latched[i] = 0;
+                self.locked |= m;
+            }
+            ModifierMode::Locked => self.latched[i] = 1,
+            ModifierMode::Held(n) => self.latched[i] = n,
+        }
+    }
+
+    /// Returns the modifiers applied to the next tapped key.
+    fn latched_modifiers(&self) -> u8 {
+        let latched = self.latched.iter().enumerate().filter(|(_, &x)| x > 0);
+        latched.fold(self.locked, |r, (i, _)| r | 1 << i)
+    }
+
+    fn release_all(&mut self) {
+        self.out = Output::default();
+        self.send(self.out);
+    }
+
+    /// Queues a report if it changed.
+    fn send(&mut self, report: Output) {
+        if report != self.sent {
+            self.sent = report;
+            self.queue.push_back(Event::Keyboard(report));
+        }
+    }
+
+    pub fn config(&self) -> Config {
+        self.bit.config
+    }
+
+    /// Sets the configuration.
+    ///
+    /// The current letter is dropped and adaptive durations start over.
+    pub fn set_config(&mut self, config: Config) {
+        self.debounce = DebounceLayer::new(config);
+        self.bit = BitLayer::new(config);
+        self.seq.state = 0;
+        self.modifier = config.modifier;
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    pub fn keymap(&self) -> &Keymap {
+        &self.seq.keymap
+    }
+
+    /// Sets the keymap and switches to its base layer.
+    pub fn set_keymap(&mut self, keymap: Keymap) {
+        self.seq.keymap = keymap;
+        self.seq.layers.clear();
+        self.seq.momentary = None;
+    }
+
+    /// Returns the current layer.
+    ///
+    /// This is the layer of the next sequence (either momentary or the last toggled one).
+    pub fn layer(&self) -> u8 {
+        self.seq.layer()
+    }
+
+    pub fn unicode_method(&self) -> unicode::Method {
+        self.unicode
+    }
+
+    pub fn set_unicode_method(&mut self, method: unicode::Method) {
+        self.unicode = method;
+    }
+
+    pub fn host_leds(&self) -> HostLeds {
+        self.host_leds
+    }
+
+    /// Sets the keyboard LEDs as last reported by the host.
+    ///
+    /// Letters typed by macros and code points keep their case when Caps Lock is on.
+    pub fn set_host_leds(&mut self, leds: HostLeds) {
+        self.host_leds = leds;
+    }
+
+    /// Returns the active modifiers.
+    ///
+    /// Those are the pressed modifiers and the tapped modifiers applying to the next tapped key.
+    pub fn modifiers(&self) -> u8 {
+        self.out.modifiers | self.latched_modifiers()
+    }
+
+    /// Returns the locked modifiers.
+    pub fn locked_modifiers(&self) -> u8 {
+        self.locked
     }
 
     pub fn bit_state(&self) -> BitState {
@@ -318,110 +981,586 @@ impl State {
     }
 }
 
+#[cfg(test)]
+extern crate std;
+
+/// Types Morse codes (e.g. `"-.-. .-"` or `"#"` to cancel) and returns the reports.
+#[cfg(test)]
+fn run(state: &mut State, codes: &str) -> std::vec::Vec<Output> {
+    run_at(state, codes, 50)
+}
+
+/// Types Morse codes with a given short press duration (a multiple of 5).
+///
+/// Long presses and letter gaps are 3 times as long, word gaps (`/`) are 7 times as long, and the
+/// final gap is 4 times as long. Repeat presses (`=`) are 10 times as long.
+#[cfg(test)]
+fn run_at(state: &mut State, codes: &str, dit: usize) -> std::vec::Vec<Output> {
+    let events = run_events(state, codes, dit).into_iter();
+    events
+        .filter_map(|event| match event {
+            Event::Keyboard(output) => Some(output),
+            Event::Consumer(_) | Event::Mouse(_) | Event::Lag(_) => None,
+        })
+        .collect()
+}
+
+/// Types Morse codes like [`run_at()`] but returns all events.
+#[cfg(test)]
+fn run_events(state: &mut State, codes: &str, dit: usize) -> std::vec::Vec<Event> {
+    let mut result = std::vec::Vec::new();
+    let mut timestamp = 0;
+    let mut step = |state: &mut State, button, duration| {
+        for _ in 0 .. duration / 5 {
+            timestamp += 5;
+            let input = Input { timestamp: Instant::from_ticks(timestamp), button };
+            result.extend(state.step(input));
+        }
+    };
+    for x in codes.bytes() {
+        match x {
+            b'.' => step(state, true, dit),
+            b'-' => step(state, true, 3 * dit),
+            b' ' => step(state, false, 2 * dit),
+            b'#' => step(state, true, 6 * dit),
+            b'/' => step(state, false, 6 * dit),
+            b'=' => step(state, true, 10 * dit),
+            _ => unreachable!(),
+        }
+        step(state, false, dit);
+    }
+    step(state, false, 4 * dit);
+    result
+}
+
+#[cfg(test)]
+fn test_config() -> Config {
+    let modifier = ModifierMode::OneShot;
+    let debounce = Debounce::None;
+    Config {
+        clock: Clock { frequency: 1000, maximum: usize::MAX },
+        long: ms(100),
+        cancel: ms(250),
+        letter: ms(100),
+        word: None,
+        repeat: None,
+        adaptive: None,
+        debounce,
+        lag: Lag::Ignore,
+        modifier,
+    }
+}
+
+#[cfg(test)]
+fn ms(millis: u32) -> Duration {
+    Duration::from_millis(millis)
+}
+
+#[cfg(test)]
+fn test_state(keymap: Keymap) -> State {
+    let mut state = State::new(test_config());
+    state.set_keymap(keymap);
+    state
+}
+
+#[cfg(test)]
+fn report(modifiers: u8, keys: &[u8]) -> Output {
+    let mut output = Output { modifiers, keys: [0; 6] };
+    output.keys[.. keys.len()].copy_from_slice(keys);
+    output
+}
+
 #[test]
-fn keycodes() {
-    #[track_caller]
-    fn test(xs: &[u8], s: usize, q: Seq) {
-        let mut r = 0;
-        for &x in xs {
-            match x {
-                b'.' => r = 2 * r + 1,
-                b'-' => r = 2 * r + 2,
-                _ => unreachable!(),
-            }
+fn tap() {
+    let mut state = test_state(Keymap::default());
+    // Shift+A then B
+    let output = run(&mut state, "..-.. .- -...");
+    assert_eq!(output, [report(2, &[4]), report(0, &[]), report(0, &[5]), report(0, &[])]);
+}
+
+#[test]
+fn chord() {
+    use Action::*;
+    let mut builder = Keymap::builder();
+    builder.chord(".", &[Tap(224), Tap(226), Tap(76)]).unwrap();
+    builder.map("-", PrepareSequence).unwrap().map("--", CommitSequence).unwrap();
+    builder.map("..", Tap(225)).unwrap().map(".-", Tap(4)).unwrap();
+    builder.map("-.", Press(5)).unwrap().map("---", Tap(5)).unwrap();
+    let mut state = test_state(builder.build());
+    // Ctrl+Alt+Del
+    let output = run(&mut state, ".");
+    assert_eq!(output, [report(1, &[]), report(5, &[]), report(5, &[76]), report(0, &[])]);
+    // Shift+A in a sequence
+    let output = run(&mut state, "- .. .- --");
+    assert_eq!(output, [report(2, &[]), report(2, &[4]), report(0, &[])]);
+    // Hold B
+    let output = run(&mut state, "-. .-");
+    assert_eq!(output, [report(0, &[5]), report(0, &[5, 4]), report(0, &[5])]);
+    // Tapping B while held
+    let output = run(&mut state, "---");
+    assert_eq!(output, [report(0, &[]), report(0, &[5])]);
+    // Cancel releases B
+    let output = run(&mut state, "#");
+    assert_eq!(output, [report(0, &[])]);
+}
+
+#[test]
+fn rollover() {
+    let mut builder = Keymap::builder();
+    for (i, sequence) in [".", "-", "..", ".-", "-.", "--", "..."].iter().enumerate() {
+        builder.map(sequence, Action::Press(4 + i as u8)).unwrap();
+    }
+    builder.map("---", Action::Release(5)).unwrap();
+    let mut state = test_state(builder.build());
+    let output = run(&mut state, ". - .. .- -. -- ... ---");
+    assert_eq!(
+        output,
+        [
+            report(0, &[4]),
+            report(0, &[4, 5]),
+            report(0, &[4, 5, 6]),
+            report(0, &[4, 5, 6, 7]),
+            report(0, &[4, 5, 6, 7, 8]),
+            report(0, &[4, 5, 6, 7, 8, 9]),
+            report(0, &[4, 6, 7, 8, 9]),
+        ]
+    );
+    assert_eq!(output[6].report(), [0, 0, 4, 6, 7, 8, 9, 0]);
+}
+
+#[test]
+fn macros() {
+    let mut builder = Keymap::builder();
+    builder.text(".", "Hi!").unwrap();
+    // Ctrl+Shift+U 2 0 a c <space> for the euro sign.
+    builder.chord("-", &[Action::Tap(224), Action::Tap(225), Action::Tap(24)]).unwrap();
+    let mut euro = ascii::text("20ac ").unwrap();
+    euro.insert(0, Action::Chord(0));
+    builder.macro_("..", &euro).unwrap();
+    let mut state = test_state(builder.build());
+    let output = run(&mut state, ".");
+    assert_eq!(
+        output,
+        [
+            report(2, &[11]),
+            report(0, &[]),
+            report(0, &[12]),
+            report(0, &[]),
+            report(2, &[30]),
+            report(0, &[]),
+        ]
+    );
+    let output = run(&mut state, "..");
+    assert_eq!(
+        output,
+        [
+            report(1, &[]),
+            report(3, &[]),
+            report(3, &[24]),
+            report(0, &[]),
+            report(0, &[31]),
+            report(0, &[]),
+            report(0, &[39]),
+            report(0, &[]),
+            report(0, &[4]),
+            report(0, &[]),
+            report(0, &[6]),
+            report(0, &[]),
+            report(0, &[44]),
+            report(0, &[]),
+        ]
+    );
+}
+
+#[test]
+fn unicode() {
+    let mut builder = Keymap::builder();
+    builder.map(".", Action::Unicode('é')).unwrap();
+    builder.map("-", Action::NextUnicodeMethod).unwrap();
+    builder.map("--", Action::PrepareSequence).unwrap();
+    let mut state = test_state(builder.build());
+    let output = run(&mut state, ".");
+    assert_eq!(
+        output,
+        [
+            report(3, &[24]),
+            report(0, &[]),
+            report(0, &[8]),
+            report(0, &[]),
+            report(0, &[38]),
+            report(0, &[]),
+            report(0, &[44]),
+            report(0, &[]),
+        ]
+    );
+    assert_eq!(run(&mut state, "-"), []);
+    assert_eq!(state.unicode_method(), unicode::Method::Windows);
+    // Sequences don't affect code points.
+    let output = run(&mut state, "-- .");
+    assert_eq!(
+        output,
+        [
+            report(4, &[]),
+            report(4, &[87]),
+            report(4, &[]),
+            report(4, &[8]),
+            report(4, &[]),
+            report(4, &[97]),
+            report(4, &[]),
+            report(0, &[]),
+        ]
+    );
+}
+
+#[test]
+fn modifiers() {
+    // . is Shift, - is Ctrl, .. is A, and ... is B.
+    let mut builder = Keymap::builder();
+    builder.map(".", Action::Tap(225)).unwrap().map("-", Action::Tap(224)).unwrap();
+    builder.map("..", Action::Tap(4)).unwrap().map("...", Action::Tap(5)).unwrap();
+    let keymap = builder.build();
+    let mut state = State::new(Config { modifier: ModifierMode::Locked, ..test_config() });
+    state.set_keymap(keymap.clone());
+    let output = run(&mut state, ". .. ...");
+    assert_eq!(output, [report(2, &[4]), report(0, &[]), report(0, &[5]), report(0, &[])]);
+    assert_eq!(run(&mut state, ". . -"), []);
+    assert_eq!((state.modifiers(), state.locked_modifiers()), (3, 2));
+    let output = run(&mut state, ".. ...");
+    assert_eq!(output, [report(3, &[4]), report(0, &[]), report(2, &[5]), report(0, &[])]);
+    assert_eq!((state.modifiers(), state.locked_modifiers()), (2, 2));
+    assert_eq!(run(&mut state, ". .."), [report(0, &[4]), report(0, &[])]);
+    assert_eq!(state.modifiers(), 0);
+
+    let mut state = State::new(Config { modifier: ModifierMode::Held(2), ..test_config() });
+    state.set_keymap(keymap);
+    let output = run(&mut state, ". .. ... ..");
+    assert_eq!(
+        output[.. 5],
+        [report(2, &[4]), report(0, &[]), report(2, &[5]), report(0, &[]), report(0, &[4])]
+    );
+    assert_eq!(run(&mut state, "- #"), []);
+    assert_eq!(state.modifiers(), 0);
+}
+
+#[test]
+fn modifier_macros() {
+    // . is Shift, .. types "Hi", and ... types "HH".
+    let mut builder = Keymap::builder();
+    builder.map(".", Action::Tap(225)).unwrap();
+    builder.text("..", "Hi").unwrap().text("...", "HH").unwrap();
+    let keymap = builder.build();
+    let hi = [report(2, &[11]), report(0, &[]), report(0, &[12]), report(0, &[])];
+    let hh = [report(2, &[11]), report(0, &[]), report(2, &[11]), report(0, &[])];
+
+    let mut state = State::new(Config { modifier: ModifierMode::Locked, ..test_config() });
+    state.set_keymap(keymap.clone());
+    assert_eq!(run(&mut state, ".."), hi);
+    assert_eq!(run(&mut state, "..."), hh);
+    assert_eq!(run(&mut state, ". ..."), hh);
+    assert_eq!((state.modifiers(), state.locked_modifiers()), (0, 0));
+
+    let mut state = State::new(Config { modifier: ModifierMode::Held(2), ..test_config() });
+    state.set_keymap(keymap);
+    assert_eq!(run(&mut state, ".."), hi);
+    assert_eq!(run(&mut state, "..."), hh);
+    assert_eq!(state.modifiers(), 0);
+}
+
+#[test]
+fn layers() {
+    use Action::*;
+    let mut builder = Keymap::builder();
+    builder.layer(2).map(".", Tap(6)).unwrap();
+    builder.layer(1).map(".", Tap(5)).unwrap().map("--", ToggleLayer(1)).unwrap();
+    builder.map("-.", ToggleLayer(2)).unwrap();
+    builder.layer(0).map(".", Tap(4)).unwrap().map("..", Tap(7)).unwrap();
+    builder.map("-", Layer(1)).unwrap().map("--", ToggleLayer(1)).unwrap();
+    let mut state = test_state(builder.build());
+    let a = [report(0, &[4]), report(0, &[])];
+    let b = [report(0, &[5]), report(0, &[])];
+    let c = [report(0, &[6]), report(0, &[])];
+    let d = [report(0, &[7]), report(0, &[])];
+    assert_eq!(run(&mut state, "."), a);
+    // Momentary layer.
+    assert_eq!(run(&mut state, "-"), []);
+    assert_eq!(state.layer(), 1);
+    assert_eq!(run(&mut state, "."), b);
+    assert_eq!(state.layer(), 0);
+    assert_eq!(run(&mut state, "."), a);
+    // Fallback to the base layer.
+    assert_eq!(run(&mut state, "- .."), d);
+    assert_eq!(state.layer(), 0);
+    // Toggled layers.
+    assert_eq!(run(&mut state, "-- . .."), [b, d].concat());
+    assert_eq!(run(&mut state, "-. . .."), [c, d].concat());
+    assert_eq!(state.layer(), 2);
+    assert_eq!(run(&mut state, "-. ."), b);
+    assert_eq!(state.layer(), 1);
+    assert_eq!(run(&mut state, "-. -- ."), c);
+    assert_eq!(state.layer(), 2);
+    // Cancel forgets the momentary layer.
+    assert_eq!(run(&mut state, "- #"), []);
+    assert_eq!(state.layer(), 2);
+    state.set_keymap(Keymap::default());
+    assert_eq!(state.layer(), 0);
+}
+
+#[test]
+fn adaptive() {
+    let config = test_config();
+    let adaptive = Some(Adaptive { minimum: ms(40), maximum: ms(400) });
+    let c_a = [report(0, &[6]), report(0, &[]), report(0, &[4]), report(0, &[])];
+
+    // A slow typist has cancelled long presses with fixed durations.
+    let mut state = State::new(config);
+    assert_eq!(run_at(&mut state, "-.-. .-", 70), c_a);
+    assert_ne!(run_at(&mut state, "-.-. .-", 100), c_a);
+    // But not with adaptive durations, as long as the slowdown is progressive.
+    let mut state = State::new(Config { adaptive, ..config });
+    for dit in [70, 90, 110, 130] {
+        assert_eq!(run_at(&mut state, "-.-. .-", dit), c_a, "{}", dit);
+    }
+    assert_eq!((state.bit.long, state.bit.cancel, state.bit.letter), (260, 650, 260));
+
+    // A fast typist has merged letters and misread long presses with fixed durations.
+    let mut state = State::new(config);
+    assert_eq!(run_at(&mut state, "-.-. .-", 40), c_a);
+    assert_ne!(run_at(&mut state, "-.-. .-", 30), c_a);
+    // But not with adaptive durations, as long as the speedup is progressive.
+    let mut state = State::new(Config { adaptive, ..config });
+    for dit in [40, 30, 25, 20] {
+        assert_eq!(run_at(&mut state, "-.-. .-", dit), c_a, "{}", dit);
+    }
+    assert_eq!((state.bit.long, state.bit.cancel, state.bit.letter), (40, 100, 40));
+}
+
+#[test]
+fn bit_transitions() {
+    use BitState::*;
+    let none = test_config();
+    let word = Config { word: Some(ms(300)), ..none };
+    let repeat = Some(crate::Repeat { window: ms(100), delay: ms(200), interval: ms(50) });
+    let repeat = Config { repeat, ..none };
+    #[rustfmt::skip]
+    let table = [
+        // state, button, timestamp, config, armed, next state, bit
+        (Ready, false, 10, none, false, Ready, None),
+        (Ready, true, 10, none, false, Short, None),
+        (Short, false, 50, none, false, Done, Some(Bit::Zero)),
+        (Short, true, 50, none, false, Short, None),
+        (Short, true, 150, none, false, Long, None),
+        (Long, false, 150, none, false, Done, Some(Bit::One)),
+        (Long, true, 150, none, false, Long, None),
+        (Long, true, 300, none, false, Cancel, None),
+        (Long, true, 250, repeat, false, Long, None),
+        (Long, true, 150, repeat, true, Long, None),
+        (Long, true, 250, repeat, true, Repeat, Some(Bit::Repeat)),
+        (Cancel, false, 350, none, false, Ready, Some(Bit::Cancel)),
+        (Cancel, true, 350, none, false, Cancel, None),
+        (Done, false, 50, none, false, Done, None),
+        (Done, false, 150, none, false, Ready, Some(Bit::End)),
+        (Done, false, 150, word, false, Gap, Some(Bit::End)),
+        (Done, false, 150, repeat, false, Gap, Some(Bit::End)),
+        (Done, true, 50, none, false, Short, None),
+        (Gap, false, 250, word, false, Gap, None),
+        (Gap, false, 350, word, false, Ready, Some(Bit::Word)),
+        (Gap, false, 150, repeat, false, Gap, None),
+        (Gap, false, 250, repeat, false, Ready, None),
+        (Gap, true, 250, word, false, Short, None),
+        (Repeat, false, 30, repeat, false, Ready, None),
+        (Repeat, true, 30, repeat, false, Repeat, None),
+        (Repeat, true, 60, repeat, false, Repeat, Some(Bit::Repeat)),
+    ];
+    for (state, button, timestamp, config, armed, next, bit) in table {
+        let mut layer = BitLayer::new(config);
+        layer.state = state;
+        layer.armed = armed;
+        let timestamp = Instant::from_ticks(timestamp);
+        layer.previous = Some(timestamp);
+        assert_eq!(layer.step(Input { timestamp, button }), bit, "{:?} {}", state, button);
+        assert_eq!(layer.state, next, "{:?} {} {:?}", state, button, timestamp);
+    }
+}
+
+#[test]
+fn bit_durations() {
+    // The cancel duration is measured from the press and the word duration from the release.
+    let config = Config { cancel: ms(300), word: Some(ms(400)), ..test_config() };
+    let mut layer = BitLayer::new(config);
+    let mut step =
+        |timestamp, button| layer.step(Input { timestamp: Instant::from_ticks(timestamp), button });
+    assert_eq!(step(1000, true), None);
+    assert_eq!(step(1110, true), None);
+    assert_eq!(step(1300, true), None);
+    assert_eq!(step(1310, false), Some(Bit::One));
+    assert_eq!(step(1420, false), Some(Bit::End));
+    assert_eq!(step(1700, false), None);
+    assert_eq!(step(1720, false), Some(Bit::Word));
+    assert_eq!(step(2000, true), None);
+    assert_eq!(step(2110, true), None);
+    assert_eq!(step(2310, true), None);
+    assert_eq!(step(2320, false), Some(Bit::Cancel));
+}
+
+#[test]
+fn word() {
+    let mut state = State::new(Config { word: Some(ms(300)), ..test_config() });
+    let e = [report(0, &[8]), report(0, &[])];
+    let space = [report(0, &[44]), report(0, &[])];
+    assert_eq!(run(&mut state, ". ."), [e, e].concat());
+    assert_eq!(run(&mut state, ". /."), [e, space, e].concat());
+    assert_eq!(run(&mut state, ". /"), [e, space].concat());
+    // Cancel does not end the word.
+    assert_eq!(run(&mut state, "# /"), []);
+    let mut keymap = Keymap::builder();
+    keymap.map(".", Action::Tap(8)).unwrap().word(Action::Tap(40)).unwrap();
+    state.set_keymap(keymap.build());
+    assert_eq!(run(&mut state, ". /"), [e, [report(0, &[40]), report(0, &[])]].concat());
+}
+
+#[test]
+fn valid_config() {
+    let config = test_config();
+    assert!(config.is_valid());
+    assert!(!Config { long: ms(0), ..config }.is_valid());
+    assert!(!Config { cancel: ms(100), ..config }.is_valid());
+    assert!(!Config { letter: ms(0), ..config }.is_valid());
+    assert!(Config { word: Some(ms(101)), ..config }.is_valid());
+    assert!(!Config { word: Some(ms(100)), ..config }.is_valid());
+    let repeat = |window, delay, interval| Some(Repeat { window, delay, interval });
+    assert!(Config { repeat: repeat(ms(100), ms(200), ms(50)), ..config }.is_valid());
+    assert!(!Config { repeat: repeat(ms(0), ms(200), ms(50)), ..config }.is_valid());
+    assert!(!Config { repeat: repeat(ms(100), ms(100), ms(50)), ..config }.is_valid());
+    assert!(!Config { repeat: repeat(ms(100), ms(250), ms(50)), ..config }.is_valid());
+    assert!(!Config { repeat: repeat(ms(100), ms(200), ms(0)), ..config }.is_valid());
+}
+
+#[test]
+fn repeat() {
+    let repeat = Some(Repeat { window: ms(100), delay: ms(200), interval: ms(100) });
+    let mut state = State::new(Config { repeat, ..test_config() });
+    let e = [report(0, &[8]), report(0, &[])];
+    let t = [report(0, &[23]), report(0, &[])];
+    // Holding right after a letter repeats it after the delay and then at each interval.
+    assert_eq!(run(&mut state, ". ="), [e, e, e, e].concat());
+    // Long presses right after a letter are still long presses.
+    assert_eq!(run(&mut state, ". -"), [e, t].concat());
+    // Holding later cancels.
+    assert_eq!(run(&mut state, ". /="), e);
+    // Only actions typing or moving something repeat (not e.g. switching the input method).
+    let mut keymap = Keymap::builder();
+    keymap.map(".", Action::Tap(8)).unwrap().map("..", Action::NextUnicodeMethod).unwrap();
+    state.set_keymap(keymap.build());
+    assert_eq!(run(&mut state, ".. ="), []);
+    assert_eq!(state.unicode_method(), unicode::Method::Windows);
+}
+
+#[test]
+fn consumer() {
+    let mut keymap = Keymap::builder();
+    keymap.map(".", Action::Tap(8)).unwrap().map("-", Action::Consumer(consumer::MUTE)).unwrap();
+    keymap.chord("--", &[Action::Tap(224), Action::Consumer(consumer::PLAY_PAUSE)]).unwrap();
+    let mut state = test_state(keymap.build());
+    let mute = [Event::Consumer(consumer::MUTE), Event::Consumer(0)];
+    let e = [Event::Keyboard(report(0, &[8])), Event::Keyboard(report(0, &[]))];
+    assert_eq!(run_events(&mut state, "- .", 50), [mute, e].concat());
+    // Consumer usages are tapped independently of pressed keys.
+    let play = [Event::Consumer(consumer::PLAY_PAUSE), Event::Consumer(0)];
+    let ctrl = [Event::Keyboard(report(0x01, &[])), Event::Keyboard(report(0, &[]))];
+    assert_eq!(run_events(&mut state, "--", 50), [&ctrl[.. 1], &play, &ctrl[1 ..]].concat());
+}
+
+#[test]
+fn mouse() {
+    let repeat = Some(Repeat { window: ms(100), delay: ms(200), interval: ms(100) });
+    let mut state = State::new(Config { repeat, ..test_config() });
+    let mut keymap = Keymap::builder();
+    keymap.map(".", Action::Move(2, -1)).unwrap().map("-", Action::Scroll(0, 1)).unwrap();
+    keymap.map("..", Action::Click(1)).unwrap().map(".-", Action::Drag(2)).unwrap();
+    keymap.map("-.", Action::Tap(8)).unwrap();
+    state.set_keymap(keymap.build());
+    let mouse = |buttons, x, y, wheel| Event::Mouse(Mouse { buttons, x, y, wheel, pan: 0 });
+    let e = [Event::Keyboard(report(0, &[8])), Event::Keyboard(report(0, &[]))];
+    assert_eq!(
+        run_events(&mut state, ".. -", 50),
+        [mouse(1, 0, 0, 0), mouse(0, 0, 0, 0), mouse(0, 0, 0, 1)]
+    );
+    // Repeated moves accelerate.
+    let moves = [mouse(0, 2, -1, 0), mouse(0, 4, -2, 0), mouse(0, 6, -3, 0), mouse(0, 8, -4, 0)];
+    assert_eq!(run_events(&mut state, ". =", 50), moves);
+    // Held buttons are kept while moving and released when cancelling.
+    let drag = [mouse(2, 0, 0, 0), mouse(2, 2, -1, 0)];
+    assert_eq!(run_events(&mut state, ".- . -.", 50), [&drag[..], &e].concat());
+    assert_eq!(run_events(&mut state, "#", 50), [mouse(0, 0, 0, 0)]);
+}
+
+#[test]
+fn config_stats() {
+    let mut state = test_state(Keymap::default());
+    assert_eq!(run(&mut state, ".- #"), [report(0, &[4]), report(0, &[])]);
+    assert_eq!(state.stats(), Stats { presses: 2, actions: 2, cancels: 1, max_lag: ms(0) });
+    // Long presses are short with a longer duration.
+    state.set_config(Config { long: ms(200), ..state.config() });
+    assert_eq!(run(&mut state, "-"), [report(0, &[8]), report(0, &[])]);
+}
+
+#[test]
+fn lag() {
+    let e = Event::Keyboard(report(0, &[8]));
+    let t = Event::Keyboard(report(0, &[23]));
+    let none = Event::Keyboard(report(0, &[]));
+    let lag = |millis| Event::Lag(ms(millis));
+    // Steps every 10 units (or at once when jumping) until a timestamp (wrapping around at 1000).
+    let step = |state: &mut State, now: &mut usize, until, button, jump| {
+        let mut events = Vec::new();
+        while *now < until {
+            *now = if jump { until } else { *now + 10 };
+            let timestamp = Instant::from_ticks(*now % 1000);
+            events.extend(state.step(Input { timestamp, button }));
         }
-        assert_eq!(r, s);
-        assert_eq!(Seq::from(MAP[r]), q);
-    }
-    // Use https://www.win.tue.nl/~aeb/linux/kbd/scancodes-14.html to check the key.
-    use Seq::*;
-    test(b"", 0, Key(0));
-
-    // Official codes
-    test(b".-", 4, Key(4)); // A
-    test(b"-...", 23, Key(5)); // B
-    test(b"-.-.", 25, Key(6)); // C
-    test(b"-..", 11, Key(7)); // D
-    test(b".", 1, Key(8)); // E
-    test(b"..-.", 17, Key(9)); // F
-    test(b"--.", 13, Key(10)); // G
-    test(b"....", 15, Key(11)); // H
-    test(b"..", 3, Key(12)); // I
-    test(b".---", 22, Key(13)); // J
-    test(b"-.-", 12, Key(14)); // K
-    test(b".-..", 19, Key(15)); // L
-    test(b"--", 6, Key(16)); // M
-    test(b"-.", 5, Key(17)); // N
-    test(b"---", 14, Key(18)); // O
-    test(b".--.", 21, Key(19)); // P
-    test(b"--.-", 28, Key(20)); // Q
-    test(b".-.", 9, Key(21)); // R
-    test(b"...", 7, Key(22)); // S
-    test(b"-", 2, Key(23)); // T
-    test(b"..-", 8, Key(24)); // U
-    test(b"...-", 16, Key(25)); // V
-    test(b".--", 10, Key(26)); // W
-    test(b"-..-", 24, Key(27)); // X
-    test(b"-.--", 26, Key(28)); // Y
-    test(b"--..", 27, Key(29)); // Z
-    test(b".----", 46, Key(30)); // 1
-    test(b"..---", 38, Key(31)); // 2
-    test(b"...--", 34, Key(32)); // 3
-    test(b"....-", 32, Key(33)); // 4
-    test(b".....", 31, Key(34)); // 5
-    test(b"-....", 47, Key(35)); // 6
-    test(b"--...", 55, Key(36)); // 7
-    test(b"---..", 59, Key(37)); // 8
-    test(b"----.", 61, Key(38)); // 9
-    test(b"-----", 62, Key(39)); // 0
-
-    // Custom codes
-    test(b"..--", 18, Key(44)); // Space
-    test(b".-.-", 20, Key(42)); // BSp
-    test(b"---.", 29, Key(43)); // Tab
-    test(b"----", 30, Key(40)); // Enter
-    test(b"...-.", 33, Modifier(1)); // LCtrl
-    test(b"..-..", 35, Modifier(2)); // LShift
-    test(b".-...", 39, Modifier(4)); // LAlt
-    test(b"..-.-", 36, Key(41)); // Esc
-    test(b"..--.", 37, Key(45)); // -/_
-    test(b".-..-", 40, Key(46)); // =/+
-    test(b".-.-.", 41, Key(47)); // [/{
-    test(b".-.--", 42, Key(48)); // ]/}
-    test(b".--..", 43, Key(49)); // \/|
-    test(b".--.-", 44, Key(51)); // ;/:
-    test(b".---.", 45, Key(52)); // '/"
-    test(b"-...-", 48, Key(53)); // `/~
-    test(b"-..-.", 49, Key(54)); // ,/<
-    test(b"-..--", 50, Key(55)); // ./>
-    test(b"-.-..", 51, Key(56)); // //?
-    test(b"-.-.-", 52, Key(76)); // Delete
-    test(b"-.--.", 53, Key(101)); // Applic (but actually Menu)
-    test(b".....-", 64, Key(79)); // Right
-    test(b"....-.", 65, Key(82)); // Up
-    test(b"...-..", 67, Key(81)); // Down
-    test(b"..-...", 71, Key(80)); // Left
-    test(b".-...-", 80, Key(77)); // End
-    test(b".-..-.", 81, Key(75)); // PgUp
-    test(b".-.-..", 83, Key(78)); // PgDown
-    test(b".--...", 87, Key(74)); // Home
-    test(b"-....-", 96, Key(58)); // F1
-    test(b"-...-.", 97, Key(59)); // F2
-    test(b"-...--", 98, Key(60)); // F3
-    test(b"-..-..", 99, Key(61)); // F4
-    test(b"-..-.-", 100, Key(62)); // F5
-    test(b"-..--.", 101, Key(63)); // F6
-    test(b"-..---", 102, Key(64)); // F7
-    test(b"-.-...", 103, Key(65)); // F8
-    test(b"-.-..-", 104, Key(66)); // F9
-    test(b"-.-.-.", 105, Key(67)); // F10
-    test(b"-.-.--", 106, Key(68)); // F11
-    test(b"-.--..", 107, Key(69)); // F12
-    test(b"--....", 111, Key(70)); // PrtScr
-    test(b"--...-", 112, Key(72)); // Pause
-    test(b"--..-.", 113, Key(73)); // Insert
-    test(b"--..--", 114, Key(154)); // SysRq
-    test(b"---..-", 120, Key(57)); // Caps Lock
-    test(b"---.-.", 121, Key(83)); // Num Lock
-    test(b"---.--", 122, Key(71)); // Scroll Lock
+        events
+    };
+    let clock = Clock { frequency: 1000, maximum: 999 };
+    for (policy, letter) in [(Lag::Ignore, t), (Lag::Clamp, e)] {
+        let mut state = State::new(Config { clock, lag: policy, ..test_config() });
+        let now = &mut 900;
+        // A short press stalls for 200 units.
+        assert_eq!(step(&mut state, now, 920, true, false), []);
+        assert_eq!(step(&mut state, now, 1120, true, true), [lag(200)]);
+        // Either the stall counts and the press is long, or it only counts for 25 units.
+        assert_eq!(step(&mut state, now, 1400, false, false), [letter, none]);
+    }
+    // The second press of a letter stalls, so the letter is dropped and the press starts over.
+    let mut state = State::new(Config { clock, lag: Lag::Reset, ..test_config() });
+    let now = &mut 0;
+    assert_eq!(step(&mut state, now, 20, true, false), []);
+    assert_eq!(step(&mut state, now, 60, false, false), []);
+    assert_eq!(step(&mut state, now, 80, true, false), []);
+    assert_eq!(step(&mut state, now, 380, true, true), [lag(300)]);
+    assert_eq!(step(&mut state, now, 420, true, false), []);
+    assert_eq!(step(&mut state, now, 600, false, false), [e, none]);
+    // Inputs don't lag when ready.
+    assert_eq!(step(&mut state, now, 5000, false, true), []);
+    assert_eq!(step(&mut state, now, 5010, true, false), []);
+}
+
+#[test]
+fn host_leds() {
+    assert_eq!(HostLeds::parse(&[]), None);
+    assert_eq!(HostLeds::parse(&[0, 0]), None);
+    let caps_lock = HostLeds { caps_lock: true, ..HostLeds::default() };
+    assert_eq!(HostLeds::parse(&[0x02]), Some(caps_lock));
+    assert_eq!(HostLeds::parse(&[0x1f]).map(|x| x.kana && x.num_lock), Some(true));
+    let mut builder = Keymap::builder();
+    builder.map(".", Action::Tap(4)).unwrap().text("-", "aB").unwrap();
+    builder.macro_("..", &[Action::Tap(224), Action::Tap(4)]).unwrap();
+    let mut state = test_state(builder.build());
+    state.set_host_leds(caps_lock);
+    assert_eq!(state.host_leds(), caps_lock);
+    // Letters from sequences follow Caps Lock.
+    assert_eq!(run(&mut state, "."), [report(0, &[4]), report(0, &[])]);
+    // Letters from macros don't, unless they are shortcuts.
+    let output = [report(2, &[4]), report(0, &[]), report(0, &[5]), report(0, &[])];
+    assert_eq!(run(&mut state, "-"), output);
+    assert_eq!(run(&mut state, ".."), [report(1, &[4]), report(0, &[])]);
 }
@@ -19,122 +19,600 @@ extern crate alloc;
 
 mod board;
 
+/// USB identification of the device (see `build.rs`).
+mod usb {
+    include!(concat!(env!("OUT_DIR"), "/usb.rs"));
+}
+
 // TODO: Allow configuration with as mass storage (e.g. a file with mapping, or one file per
 // mapping). Look into https://github.com/cs2dsb/stm32-usb.rs.
 
 // TODO: Use NFC to display current mapping (or configuration) as text. And switch between
 // pre-configured mappings (or to configure new mapping? is it possible?).
 
-// TODO: Move library in separate crate and make it interrupt driven (button, time, usb).
-
-#[rtic::app(device = crate::board::pac, peripherals = true)]
-mod app {
-    use crate::board::{Board, BoardApi};
-    use alloc_cortex_m::CortexMHeap;
-    use defmt::Debug2Format;
-    #[cfg(feature = "log")]
-    use defmt_rtt as _;
-    #[cfg(not(feature = "log"))]
-    use panic_abort as _;
-    #[cfg(feature = "log")]
-    use panic_probe as _;
-    use usb_device::device::{UsbDevice, UsbDeviceBuilder, UsbVidPid};
-    use usbd_hid::descriptor::{KeyboardReport, SerializedDescriptor};
-    use usbd_hid::hid_class::HIDClass;
-    use usbd_hid::UsbError;
-
-    #[global_allocator]
-    static ALLOCATOR: CortexMHeap = CortexMHeap::empty();
-
-    #[shared]
-    struct Shared {}
-
-    #[local]
-    struct Local {
-        board: Board,
-        usb: Usb,
-        state: onekibu::State,
-    }
-
-    #[init]
-    fn init(c: init::Context) -> (Shared, Local, init::Monotonics) {
-        defmt::trace!("init");
-        init_allocator();
-        let board = Board::new(c.core, c.device);
-        // TODO: Somehow show when the board is ready (USB ready), e.g. red light from here until
-        // USB ready.
-        let usb_bus = board.usb_bus();
-        let usb_hid = HIDClass::new(usb_bus, KeyboardReport::desc(), 60);
-        let usb_dev =
-            UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x04ca, 0x0020)).product("onekibu").build();
-        let usb = Usb { hid: usb_hid, dev: usb_dev };
-        let state = onekibu::State::new(board.config());
-        (Shared {}, Local { board, usb, state }, init::Monotonics())
-    }
-
-    #[idle(local = [board, usb, state])]
-    fn idle(c: idle::Context) -> ! {
-        defmt::trace!("idle");
-        let idle::LocalResources { board, usb, state } = c.local;
-        loop {
-            let key = state.step(board.input());
-            board.state(state.bit_state());
-            usb_push(usb, key);
-        }
-    }
-
-    pub struct Usb {
-        dev: UsbDevice<'static, <Board as BoardApi>::UsbBus>,
-        hid: HIDClass<'static, <Board as BoardApi>::UsbBus>,
-    }
-
-    fn usb_push(usb: &mut Usb, key: Option<onekibu::Output>) {
-        usb_poll(usb);
-        let mut key = match key {
-            None => return,
-            Some(x) => x,
-        };
-        loop {
-            let mut input = [0; 8];
-            input[0] = key.modifiers;
-            input[2] = key.key;
-            match usb.hid.push_raw_input(&input) {
-                Ok(len) if len != input.len() => defmt::error!("pushed only {} bytes", len),
-                Ok(_) => {
-                    defmt::trace!("push {=[u8]:#x}", &input[..]);
-                    if key.key == 0 {
+// TODO: Move library in separate crate.
+
+/// Defines the application given the interrupts of the chip.
+///
+/// RTIC binds hardware tasks to their interrupt even when they are disabled with `#[cfg]`, so the
+/// interrupts are chosen before RTIC sees the application.
+macro_rules! app {
+    (dispatcher = $dispatcher:ident, mono = $mono:ident, button = $button:ident, usb = $usb:ident) => {
+        #[rtic::app(device = crate::board::pac, peripherals = true, dispatchers = [$dispatcher])]
+        mod app {
+            use crate::board::{Board, BoardApi};
+            use alloc::boxed::Box;
+            use alloc::format;
+            use alloc::string::String;
+            use alloc_cortex_m::CortexMHeap;
+            use defmt::Debug2Format;
+            #[cfg(feature = "log")]
+            use defmt_rtt as _;
+            use onekibu::store::{Settings, Store};
+            #[cfg(not(feature = "log"))]
+            use panic_abort as _;
+            #[cfg(feature = "log")]
+            use panic_probe as _;
+            use rtic::{Monotonic, Mutex};
+            use usb_device::device::{UsbDevice, UsbDeviceBuilder, UsbDeviceState, UsbVidPid};
+            use usbd_hid::descriptor::{
+                KeyboardReport, MediaKeyboardReport, MouseReport, SerializedDescriptor,
+            };
+            use usbd_hid::hid_class::HIDClass;
+            use usbd_hid::UsbError;
+            #[cfg(feature = "console")]
+            use usbd_serial::SerialPort;
+
+            #[global_allocator]
+            static ALLOCATOR: CortexMHeap = CortexMHeap::empty();
+
+            #[monotonic(binds = $mono, default = true)]
+            type Mono = crate::board::Mono;
+
+            #[shared]
+            struct Shared {
+                board: Board,
+                usb: Usb,
+                #[lock_free]
+                state: onekibu::State,
+                #[lock_free]
+                store: Store<<Board as BoardApi>::Flash>,
+                /// Pending save of the settings, if any.
+                #[lock_free]
+                saving: Option<save::SpawnHandle>,
+            }
+
+            #[local]
+            struct Local {}
+
+            #[init]
+            fn init(c: init::Context) -> (Shared, Local, init::Monotonics) {
+                defmt::trace!("init");
+                init_allocator();
+                let (mut board, mono) = Board::new(c.core, c.device);
+                // TODO: Somehow show when the board is ready (USB ready), e.g. red light from here
+                // until USB ready.
+                let usb_bus = board.usb_bus();
+                let keyboard = HIDClass::new(usb_bus, KeyboardReport::desc(), 60);
+                let consumer = HIDClass::new(usb_bus, MediaKeyboardReport::desc(), 60);
+                let mouse = HIDClass::new(usb_bus, MouseReport::desc(), 10);
+                let vendor = HIDClass::new(usb_bus, VENDOR_DESCRIPTOR, 10);
+                #[cfg(feature = "console")]
+                let serial = SerialPort::new(usb_bus);
+                // The serial number distinguishes devices plugged on the same host.
+                let serial_number: String =
+                    board.device_id().iter().map(|x| format!("{x:02X}")).collect();
+                let usb_dev =
+                    UsbDeviceBuilder::new(usb_bus, UsbVidPid(crate::usb::VID, crate::usb::PID))
+                        .manufacturer(crate::usb::MANUFACTURER)
+                        .product(crate::usb::PRODUCT)
+                        .serial_number(Box::leak(serial_number.into_boxed_str()))
+                        .supports_remote_wakeup(true);
+                // The serial port needs an interface association to be composite.
+                #[cfg(feature = "console")]
+                let usb_dev = usb_dev.composite_with_iads();
+                let usb = Usb {
+                    dev: usb_dev.build(),
+                    keyboard,
+                    consumer,
+                    mouse,
+                    vendor,
+                    #[cfg(feature = "console")]
+                    serial,
+                    #[cfg(feature = "console")]
+                    line: String::new(),
+                    suspended: false,
+                    leds: onekibu::HostLeds::default(),
+                };
+                let mut state = onekibu::State::new(board.config());
+                let store = Store::new(board.flash());
+                match store.load() {
+                    Some(settings) => {
+                        defmt::info!("Loaded settings");
+                        settings.apply(&mut state);
+                    }
+                    None => defmt::info!("Using default settings"),
+                }
+                let shared = Shared { board, usb, state, store, saving: None };
+                (shared, Local {}, init::Monotonics(mono))
+            }
+
+            /// Sleeps until the next interrupt (button edge, USB, or timer).
+            ///
+            /// Tasks run in interrupts, so idle only runs once they are done. The monotonic timer only
+            /// interrupts when a task is due, so while the state is ready and USB is idle, nothing
+            /// wakes up the board.
+            #[idle(shared = [board])]
+            fn idle(c: idle::Context) -> ! {
+                defmt::trace!("idle");
+                #[cfg(feature = "sleep-stats")]
+                let mut stats = SleepStats::new(c.shared.board);
+                #[cfg(not(feature = "sleep-stats"))]
+                let _ = c; // The board is only used for sleep statistics.
+                loop {
+                    // Interrupts are masked such that the wake up is timestamped before they run.
+                    cortex_m::interrupt::free(|_| {
+                        #[cfg(feature = "sleep-stats")]
+                        let start = stats.now();
+                        cortex_m::asm::wfi();
+                        #[cfg(feature = "sleep-stats")]
+                        stats.record(start);
+                    });
+                }
+            }
+
+            /// Reports the proportion of time spent sleeping and how often the board woke up.
+            #[cfg(feature = "sleep-stats")]
+            struct SleepStats<B: Mutex<T = Board>> {
+                board: B,
+                clock: onekibu::Clock,
+                /// End of the last sleep.
+                last: Option<onekibu::Instant>,
+                /// Ticks spent awake and asleep since the last report.
+                awake: usize,
+                asleep: usize,
+                /// Number of sleeps since the last report.
+                wakeups: usize,
+            }
+
+            #[cfg(feature = "sleep-stats")]
+            impl<B: Mutex<T = Board>> SleepStats<B> {
+                fn new(mut board: B) -> Self {
+                    let clock = board.lock(|board| board.config().clock);
+                    SleepStats { board, clock, last: None, awake: 0, asleep: 0, wakeups: 0 }
+                }
+
+                fn now(&mut self) -> onekibu::Instant {
+                    self.board.lock(|board| board.now())
+                }
+
+                /// Records a sleep from its start until now.
+                fn record(&mut self, start: onekibu::Instant) {
+                    let end = self.now();
+                    if let Some(last) = self.last {
+                        self.awake += self.clock.elapsed(last, start);
+                    }
+                    self.asleep += self.clock.elapsed(start, end);
+                    self.wakeups += 1;
+                    self.last = Some(end);
+                    let total = self.awake + self.asleep;
+                    if total >= 10 * self.clock.frequency as usize {
+                        let percent = (self.asleep as u64 * 100 / total as u64) as u32;
+                        defmt::info!(
+                            "Asleep {}% of the last {} ({} wake-ups)",
+                            percent,
+                            self.clock.duration(total),
+                            self.wakeups
+                        );
+                        self.awake = 0;
+                        self.asleep = 0;
+                        self.wakeups = 0;
+                    }
+                }
+            }
+
+            #[task(binds = $button, shared = [board])]
+            fn button_edge(c: button_edge::Context) {
+                button(c.shared.board);
+            }
+
+            /// Timestamps a button edge as soon as possible.
+            fn button(mut board: impl Mutex<T = Board>) {
+                let input = board.lock(|board| {
+                    board.clear_button();
+                    board.input()
+                });
+                if step::spawn(input).is_err() {
+                    defmt::warn!("Dropped button input");
+                }
+            }
+
+            /// Steps the state while it depends on time (i.e. timeouts or debouncing).
+            #[task(shared = [board])]
+            fn tick(mut c: tick::Context) {
+                let input = c.shared.board.lock(|board| board.input());
+                // The queue is only full if button edges are pending, which also step the state.
+                let _ = step::spawn(input);
+            }
+
+            /// Pending tick, if any.
+            type Tick = Option<tick::SpawnHandle>;
+
+            #[task(capacity = 8, shared = [board, usb, state], local = [tick: Tick = None])]
+            fn step(mut c: step::Context, input: onekibu::Input) {
+                let (suspended, leds) = c.shared.usb.lock(|usb| (usb.suspended, usb.leds));
+                if suspended {
+                    // The state is paused while suspended, but pressing wakes the host up.
+                    if input.button {
+                        (&mut c.shared.board, &mut c.shared.usb).lock(usb_wakeup);
+                    }
+                    return;
+                }
+                let state = c.shared.state;
+                state.set_host_leds(leds);
+                for event in state.step(input) {
+                    match event {
+                        onekibu::Event::Keyboard(output) => {
+                            let report = output.report();
+                            c.shared.usb.lock(|usb| usb_push(usb, Interface::Keyboard, &report));
+                        }
+                        onekibu::Event::Consumer(usage) => {
+                            let report = usage.to_le_bytes();
+                            c.shared.usb.lock(|usb| usb_push(usb, Interface::Consumer, &report));
+                        }
+                        onekibu::Event::Mouse(mouse) => {
+                            let report = mouse.report();
+                            c.shared.usb.lock(|usb| usb_push(usb, Interface::Mouse, &report));
+                        }
+                        onekibu::Event::Lag(duration) => {
+                            defmt::debug!("lag {}ms", duration.millis())
+                        }
+                    }
+                }
+                c.shared.board.lock(|board| board.state(state.bit_state(), state.modifiers()));
+                if let Some(handle) = c.local.tick.take() {
+                    let _ = handle.cancel();
+                }
+                if input.button || !matches!(state.bit_state(), onekibu::BitState::Ready) {
+                    *c.local.tick = tick::spawn_after(millis(TICK)).ok();
+                }
+            }
+
+            #[task(binds = $usb, priority = 2, shared = [board, usb])]
+            fn usb_event(c: usb_event::Context) {
+                usb_interrupt(c.shared.board, c.shared.usb);
+            }
+
+            /// Polls USB and handles suspend and resume.
+            fn usb_interrupt(mut board: impl Mutex<T = Board>, mut usb: impl Mutex<T = Usb>) {
+                let (was_suspended, suspended, leds) = usb.lock(|usb| {
+                    let (was_suspended, leds) = (usb.suspended, usb.leds);
+                    usb_poll(usb);
+                    (was_suspended, usb.suspended, (leds != usb.leds).then_some(usb.leds))
+                });
+                if let Some(leds) = leds {
+                    board.lock(|board| board.host_leds(leds));
+                }
+                match (was_suspended, suspended) {
+                    (false, true) => {
+                        defmt::info!("USB suspended");
+                        // Turn the LEDs off.
+                        board.lock(|board| board.state(onekibu::BitState::Ready, 0));
+                    }
+                    (true, false) => {
+                        defmt::info!("USB resumed");
+                        // Step the state to restore the LEDs.
+                        let _ = step::spawn(board.lock(|board| board.input()));
+                    }
+                    _ => (),
+                }
+            }
+
+            /// Runs a console line and writes its output.
+            ///
+            /// RTIC ignores `#[cfg]` on tasks, so this task also exists without console (but is
+            /// never spawned).
+            #[task(capacity = 2, shared = [board, usb, state, saving])]
+            fn command(mut c: command::Context, line: String) {
+                use onekibu::console::{self, Command};
+                let state = c.shared.state;
+                let mut out = String::new();
+                let _ = match console::parse(&line) {
+                    Ok(Command::Help) => console::help(&mut out),
+                    Ok(Command::Keymap) => console::keymap(&mut out, state.keymap()),
+                    Ok(Command::Macros) => console::macros(&mut out, state.keymap()),
+                    Ok(Command::Timings) => console::timings(&mut out, &state.config()),
+                    Ok(Command::Set(setting)) => {
+                        let mut config = state.config();
+                        match setting.apply(&mut config) {
+                            Ok(()) => {
+                                state.set_config(config);
+                                schedule_save(c.shared.saving);
+                                console::timings(&mut out, &config)
+                            }
+                            Err(error) => console::error(&mut out, error),
+                        }
+                    }
+                    Ok(Command::Stats) => console::stats(&mut out, &state.stats()),
+                    Ok(Command::Reboot) if !Board::BOOTLOADER => {
+                        console::error(&mut out, console::Error::Unsupported)
+                    }
+                    Ok(Command::Reboot) => c.shared.board.lock(|board| board.reboot_bootloader()),
+                    Err(error) => console::error(&mut out, error),
+                };
+                #[cfg(feature = "console")]
+                c.shared.usb.lock(|usb| console_write(usb, &out));
+            }
+
+            /// Handles a request of the vendor configuration channel and pushes its response.
+            #[task(capacity = 2, shared = [usb, state, saving])]
+            fn vendor(mut c: vendor::Context, request: [u8; onekibu::protocol::REPORT]) {
+                let response = onekibu::vendor::handle(c.shared.state, &request);
+                c.shared.usb.lock(|usb| usb_push(usb, Interface::Vendor, &response));
+                // Requests that don't change the settings don't write the flash.
+                schedule_save(c.shared.saving);
+            }
+
+            /// Saves the settings if they differ from the stored ones.
+            #[task(shared = [state, store, saving])]
+            fn save(c: save::Context) {
+                *c.shared.saving = None;
+                let settings = Settings::new(c.shared.state);
+                if c.shared.store.load().as_ref() == Some(&settings) {
+                    return;
+                }
+                match c.shared.store.save(&settings) {
+                    Ok(()) => defmt::info!("Saved settings"),
+                    Err(error) => defmt::error!("Failed to save settings: {}", error),
+                }
+            }
+
+            /// Saves the settings once they stop changing (to spare the flash).
+            fn schedule_save(saving: &mut Option<save::SpawnHandle>) {
+                let delay = millis(SAVE);
+                *saving = saving
+                    .take()
+                    .and_then(|handle| handle.reschedule_after(delay).ok())
+                    .or_else(|| save::spawn_after(delay).ok());
+            }
+
+            /// Ends the remote wakeup signal.
+            #[task(shared = [board])]
+            fn usb_wakeup_end(mut c: usb_wakeup_end::Context) {
+                c.shared.board.lock(|board| board.usb_wakeup_end());
+            }
+
+            fn usb_wakeup(board: &mut Board, usb: &mut Usb) {
+                if usb.dev.remote_wakeup_enabled() {
+                    defmt::info!("USB remote wakeup");
+                    board.usb_wakeup();
+                    // A pending end also ends this signal.
+                    let _ = usb_wakeup_end::spawn_after(millis(RESUME));
+                } else {
+                    defmt::debug!("USB remote wakeup disabled by host");
+                }
+            }
+
+            /// Converts milliseconds to a duration of the monotonic timer.
+            fn millis(millis: u64) -> <crate::board::Mono as Monotonic>::Duration {
+                <crate::board::Mono as Monotonic>::Duration::millis(millis)
+            }
+
+            /// Period in milliseconds at which the state is stepped while it depends on time.
+            ///
+            /// This must be smaller than the lag threshold (a quarter of the long press duration).
+            const TICK: u64 = 5;
+
+            /// Duration in milliseconds of the remote wakeup signal (between 1 and 15).
+            const RESUME: u64 = 5;
+
+            /// Delay in milliseconds after the last change before saving the settings.
+            const SAVE: u64 = 2000;
+
+            pub struct Usb {
+                dev: UsbDevice<'static, <Board as BoardApi>::UsbBus>,
+                keyboard: HIDClass<'static, <Board as BoardApi>::UsbBus>,
+                consumer: HIDClass<'static, <Board as BoardApi>::UsbBus>,
+                mouse: HIDClass<'static, <Board as BoardApi>::UsbBus>,
+                vendor: HIDClass<'static, <Board as BoardApi>::UsbBus>,
+                #[cfg(feature = "console")]
+                serial: SerialPort<'static, <Board as BoardApi>::UsbBus>,
+                /// Console line being typed.
+                #[cfg(feature = "console")]
+                line: String,
+                /// Whether the bus is suspended (as of the last poll).
+                suspended: bool,
+                /// Keyboard LEDs set by the host (as of the last output report).
+                leds: onekibu::HostLeds,
+            }
+
+            /// HID interface of a report.
+            #[derive(Clone, Copy)]
+            enum Interface {
+                Keyboard,
+                Consumer,
+                Mouse,
+                Vendor,
+            }
+
+            /// Pushes a report, polling until the previous one is read.
+            ///
+            /// Vendor responses are only read while the host tool runs, so they are dropped instead
+            /// of waiting (which would block the keyboard).
+            fn usb_push(usb: &mut Usb, interface: Interface, input: &[u8]) {
+                loop {
+                    usb_poll(usb);
+                    if usb.suspended {
+                        defmt::warn!("Dropped report while suspended");
                         break;
                     }
-                    key = onekibu::Output::default();
+                    let hid = match interface {
+                        Interface::Keyboard => &usb.keyboard,
+                        Interface::Consumer => &usb.consumer,
+                        Interface::Mouse => &usb.mouse,
+                        Interface::Vendor => &usb.vendor,
+                    };
+                    match hid.push_raw_input(input) {
+                        Ok(len) if len != input.len() => {
+                            defmt::error!("pushed only {} bytes", len);
+                            break;
+                        }
+                        Ok(_) => {
+                            defmt::trace!("push {=[u8]:#x}", input);
+                            break;
+                        }
+                        Err(UsbError::WouldBlock) if matches!(interface, Interface::Vendor) => {
+                            defmt::warn!("Dropped vendor response (previous one not read)");
+                            break;
+                        }
+                        Err(UsbError::WouldBlock) => (),
+                        Err(err) => {
+                            defmt::error!("push failed: {:?}", Debug2Format(&err));
+                            break;
+                        }
+                    }
                 }
-                Err(UsbError::WouldBlock) => (),
-                Err(err) => defmt::error!("push failed: {:?}", Debug2Format(&err)),
             }
-            usb_poll(usb);
-        }
-    }
 
-    fn usb_poll(usb: &mut Usb) {
-        if !usb.dev.poll(&mut [&mut usb.hid]) {
-            return;
-        }
-        let mut buf = [0; 32];
-        match usb.hid.pull_raw_output(&mut buf) {
-            Ok(len) => defmt::warn!("poll {=[u8]:#x}", &buf[.. len]),
-            Err(UsbError::WouldBlock) => (),
-            Err(err) => defmt::error!("poll failed: {:?}", Debug2Format(&err)),
-        }
-    }
+            fn usb_poll(usb: &mut Usb) {
+                #[cfg(not(feature = "console"))]
+                let events = usb.dev.poll(&mut [
+                    &mut usb.keyboard,
+                    &mut usb.consumer,
+                    &mut usb.mouse,
+                    &mut usb.vendor,
+                ]);
+                #[cfg(feature = "console")]
+                let events = usb.dev.poll(&mut [
+                    &mut usb.keyboard,
+                    &mut usb.consumer,
+                    &mut usb.mouse,
+                    &mut usb.vendor,
+                    &mut usb.serial,
+                ]);
+                usb.suspended = usb.dev.state() == UsbDeviceState::Suspend;
+                if !events {
+                    return;
+                }
+                #[cfg(feature = "console")]
+                console_read(usb);
+                let mut buf = [0; 32];
+                match usb.keyboard.pull_raw_output(&mut buf) {
+                    Ok(len) => match onekibu::HostLeds::parse(&buf[.. len]) {
+                        Some(leds) => {
+                            defmt::debug!("{}", leds);
+                            usb.leds = leds;
+                        }
+                        None => defmt::warn!("poll {=[u8]:#x}", &buf[.. len]),
+                    },
+                    Err(UsbError::WouldBlock) => (),
+                    Err(err) => defmt::error!("poll failed: {:?}", Debug2Format(&err)),
+                }
+                let mut request = [0; onekibu::protocol::REPORT];
+                match usb.vendor.pull_raw_output(&mut request) {
+                    Ok(_) if vendor::spawn(request).is_err() => {
+                        defmt::warn!("Dropped vendor request")
+                    }
+                    Ok(_) | Err(UsbError::WouldBlock) => (),
+                    Err(err) => defmt::error!("poll failed: {:?}", Debug2Format(&err)),
+                }
+            }
+
+            /// Report descriptor of the vendor configuration channel (see [`onekibu::protocol`]).
+            const VENDOR_DESCRIPTOR: &[u8] = &[
+                0x06, 0x00, 0xff, // Usage Page (Vendor Defined 0xFF00)
+                0x09, 0x01, // Usage (0x01)
+                0xa1, 0x01, // Collection (Application)
+                0x15, 0x00, //   Logical Minimum (0)
+                0x26, 0xff, 0x00, //   Logical Maximum (255)
+                0x75, 0x08, //   Report Size (8)
+                0x95, 0x40, //   Report Count (64)
+                0x09, 0x02, //   Usage (0x02)
+                0x81, 0x02, //   Input (Data, Variable, Absolute)
+                0x95, 0x40, //   Report Count (64)
+                0x09, 0x03, //   Usage (0x03)
+                0x91, 0x02, //   Output (Data, Variable, Absolute)
+                0xc0, // End Collection
+            ];
+
+            /// Maximum length of a console line.
+            #[cfg(feature = "console")]
+            const LINE: usize = 64;
 
-    fn init_allocator() {
-        extern "C" {
-            static mut __sheap: u32;
-            static mut __eheap: u32;
+            /// Reads console input, echoing it and spawning complete lines.
+            #[cfg(feature = "console")]
+            fn console_read(usb: &mut Usb) {
+                let mut buf = [0; 64];
+                let len = match usb.serial.read(&mut buf) {
+                    Ok(len) => len,
+                    Err(UsbError::WouldBlock) => return,
+                    Err(err) => {
+                        defmt::error!("read failed: {:?}", Debug2Format(&err));
+                        return;
+                    }
+                };
+                for &x in &buf[.. len] {
+                    match x {
+                        b'\r' | b'\n' => {
+                            let _ = usb.serial.write(b"\r\n");
+                            let line = core::mem::take(&mut usb.line);
+                            if !line.trim().is_empty() && command::spawn(line).is_err() {
+                                defmt::warn!("Dropped console line");
+                            }
+                        }
+                        // Backspace and delete (ignored on an empty line).
+                        0x08 | 0x7f if usb.line.pop().is_some() => {
+                            let _ = usb.serial.write(b"\x08 \x08");
+                        }
+                        b' ' ..= b'~' if usb.line.len() < LINE => {
+                            usb.line.push(x as char);
+                            let _ = usb.serial.write(&[x]);
+                        }
+                        _ => (),
+                    }
+                }
+            }
+
+            /// Writes console output, dropping it if the terminal is not connected.
+            #[cfg(feature = "console")]
+            fn console_write(usb: &mut Usb, out: &str) {
+                for line in out.lines() {
+                    for mut bytes in [line.as_bytes(), &b"\r\n"[..]] {
+                        while !bytes.is_empty() {
+                            usb_poll(usb);
+                            if usb.suspended || !usb.serial.dtr() {
+                                defmt::warn!("Dropped console output");
+                                return;
+                            }
+                            match usb.serial.write(bytes) {
+                                Ok(len) => bytes = &bytes[len ..],
+                                Err(UsbError::WouldBlock) => (),
+                                Err(err) => {
+                                    defmt::error!("write failed: {:?}", Debug2Format(&err));
+                                    return;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+
+            fn init_allocator() {
+                extern "C" {
+                    static mut __sheap: u32;
+                    static mut __eheap: u32;
+                }
+                let sheap = unsafe { &mut __sheap } as *mut u32 as usize;
+                let eheap = unsafe { &mut __eheap } as *mut u32 as usize;
+                assert!(sheap < eheap);
+                // Unsafe: Called only once before any allocation.
+                unsafe { ALLOCATOR.init(sheap, eheap - sheap) }
+            }
         }
-        let sheap = unsafe { &mut __sheap } as *mut u32 as usize;
-        let eheap = unsafe { &mut __eheap } as *mut u32 as usize;
-        assert!(sheap < eheap);
-        // Unsafe: Called only once before any allocation.
-        unsafe { ALLOCATOR.init(sheap, eheap - sheap) }
-    }
+    };
 }
+
+#[cfg(feature = "chip-nrf52840")]
+app!(dispatcher = SWI0_EGU0, mono = RTC1, button = GPIOTE, usb = USBD);
+#[cfg(feature = "board-solo")]
+app!(dispatcher = SPI1, mono = TIM2, button = EXTI0, usb = USB_FS);
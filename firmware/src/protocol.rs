@@ -0,0 +1,523 @@
+// Copyright 2021-2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Binary protocol of the vendor HID configuration channel.
+//!
+//! Each request is an output report answered by an input report. Both start with the protocol
+//! version and an opcode. Integers are little-endian. Durations are in milliseconds.
+//!
+//! This module is shared with the host tool (see `cargo xtask config`), so it only depends on
+//! `core` and `alloc`.
+
+use alloc::vec::Vec;
+
+/// Version of the protocol.
+pub const VERSION: u8 = 1;
+
+/// Size of the input and output reports.
+pub const REPORT: usize = 64;
+
+/// Size of an encoded action.
+pub const ACTION: usize = 5;
+
+/// Maximum number of actions in a list request or response.
+pub const LIST: usize = (REPORT - 6) / ACTION;
+
+/// Durations of the configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timings {
+    pub long: u32,
+    pub cancel: u32,
+    pub letter: u32,
+    pub word: Option<u32>,
+    pub repeat: Option<Repeat>,
+}
+
+/// Durations of the repeat gesture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Repeat {
+    pub window: u32,
+    pub delay: u32,
+    pub interval: u32,
+}
+
+/// Mirror of the firmware actions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Tap(u8),
+    Press(u8),
+    Release(u8),
+    Toggle(u8),
+    Consumer(u16),
+    Move(i8, i8),
+    Scroll(i8, i8),
+    Click(u8),
+    Drag(u8),
+    PrepareSequence,
+    CommitSequence,
+    Chord(u8),
+    Macro(u8),
+    Unicode(char),
+    NextUnicodeMethod,
+    Layer(u8),
+    ToggleLayer(u8),
+    Cancel,
+}
+
+/// Kind of action list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum List {
+    Chord,
+    Macro,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// Reads the number of layers, chords, and macros.
+    Info,
+
+    ReadTimings,
+
+    WriteTimings(Timings),
+
+    /// Reads the action of a sequence in a layer.
+    ///
+    /// The sequence is an index (see `keymap::parse()`). Index 0 stands for the word action.
+    ReadAction {
+        layer: u8,
+        sequence: u8,
+    },
+
+    /// Writes the action of a sequence in a layer (creating the layer if it is the next one).
+    WriteAction {
+        layer: u8,
+        sequence: u8,
+        action: Option<Action>,
+    },
+
+    /// Reads the actions of a chord or macro from an offset.
+    ReadList {
+        list: List,
+        index: u8,
+        offset: u8,
+    },
+
+    /// Replaces the actions of a chord or macro from an offset (creating it if it is the next
+    /// one).
+    ///
+    /// At most [`LIST`] actions fit in a request. Longer lists are written in multiple requests.
+    WriteList {
+        list: List,
+        index: u8,
+        offset: u8,
+        actions: Vec<Action>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Info {
+        layers: u8,
+        chords: u8,
+        macros: u8,
+    },
+
+    Timings(Timings),
+
+    Action(Option<Action>),
+
+    /// Actions from the requested offset (at most [`LIST`]) and the length of the list.
+    List {
+        length: u8,
+        actions: Vec<Action>,
+    },
+
+    /// The write succeeded.
+    Done,
+
+    Error(Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The report has another protocol version.
+    Version,
+
+    /// The report could not be decoded.
+    Malformed,
+
+    /// The request was rejected (e.g. the layer, chord, or macro does not exist, or the durations
+    /// are inconsistent).
+    Rejected,
+}
+
+impl Request {
+    pub fn encode(&self) -> [u8; REPORT] {
+        let mut writer;
+        match self {
+            Request::Info => writer = Writer::new(1),
+            Request::ReadTimings => writer = Writer::new(2),
+            Request::WriteTimings(timings) => {
+                writer = Writer::new(3);
+                writer.timings(timings);
+            }
+            &Request::ReadAction { layer, sequence } => {
+                writer = Writer::new(4);
+                writer.u8(layer);
+                writer.u8(sequence);
+            }
+            &Request::WriteAction { layer, sequence, action } => {
+                writer = Writer::new(5);
+                writer.u8(layer);
+                writer.u8(sequence);
+                writer.action(action);
+            }
+            &Request::ReadList { list, index, offset } => {
+                writer = Writer::new(6);
+                writer.list(list, index, offset);
+            }
+            Request::WriteList { list, index, offset, actions } => {
+                writer = Writer::new(7);
+                writer.list(*list, *index, *offset);
+                writer.actions(actions);
+            }
+        }
+        writer.report
+    }
+
+    pub fn decode(report: &[u8]) -> Result<Request, Error> {
+        let (mut reader, opcode) = Reader::new(report)?;
+        Ok(match opcode {
+            1 => Request::Info,
+            2 => Request::ReadTimings,
+            3 => Request::WriteTimings(reader.timings()?),
+            4 => Request::ReadAction { layer: reader.u8()?, sequence: reader.u8()? },
+            5 => Request::WriteAction {
+                layer: reader.u8()?,
+                sequence: reader.u8()?,
+                action: reader.action()?,
+            },
+            6 => {
+                let (list, index, offset) = reader.list()?;
+                Request::ReadList { list, index, offset }
+            }
+            7 => {
+                let (list, index, offset) = reader.list()?;
+                Request::WriteList { list, index, offset, actions: reader.actions()? }
+            }
+            _ => return Err(Error::Malformed),
+        })
+    }
+}
+
+impl Response {
+    pub fn encode(&self) -> [u8; REPORT] {
+        let mut writer;
+        match self {
+            &Response::Info { layers, chords, macros } => {
+                writer = Writer::new(1);
+                writer.u8(layers);
+                writer.u8(chords);
+                writer.u8(macros);
+            }
+            Response::Timings(timings) => {
+                writer = Writer::new(2);
+                writer.timings(timings);
+            }
+            &Response::Action(action) => {
+                writer = Writer::new(3);
+                writer.action(action);
+            }
+            Response::List { length, actions } => {
+                writer = Writer::new(4);
+                writer.u8(*length);
+                writer.actions(actions);
+            }
+            Response::Done => writer = Writer::new(5),
+            &Response::Error(error) => {
+                writer = Writer::new(0xff);
+                writer.u8(error as u8);
+            }
+        }
+        writer.report
+    }
+
+    pub fn decode(report: &[u8]) -> Result<Response, Error> {
+        let (mut reader, opcode) = Reader::new(report)?;
+        Ok(match opcode {
+            1 => {
+                Response::Info { layers: reader.u8()?, chords: reader.u8()?, macros: reader.u8()? }
+            }
+            2 => Response::Timings(reader.timings()?),
+            3 => Response::Action(reader.action()?),
+            4 => Response::List { length: reader.u8()?, actions: reader.actions()? },
+            5 => Response::Done,
+            0xff => Response::Error(match reader.u8()? {
+                0 => Error::Version,
+                1 => Error::Malformed,
+                2 => Error::Rejected,
+                _ => return Err(Error::Malformed),
+            }),
+            _ => return Err(Error::Malformed),
+        })
+    }
+}
+
+/// Encodes an optional action (a tag followed by a value).
+pub fn encode_action(action: Option<Action>) -> [u8; ACTION] {
+    use Action::*;
+    let pair = |x: i8, y: i8| u32::from_le_bytes([x as u8, y as u8, 0, 0]);
+    let (tag, value) = match action {
+        None => (0, 0),
+        Some(Tap(x)) => (1, x as u32),
+        Some(Press(x)) => (2, x as u32),
+        Some(Release(x)) => (3, x as u32),
+        Some(Toggle(x)) => (4, x as u32),
+        Some(Consumer(x)) => (5, x as u32),
+        Some(Move(x, y)) => (6, pair(x, y)),
+        Some(Scroll(x, y)) => (7, pair(x, y)),
+        Some(Click(x)) => (8, x as u32),
+        Some(Drag(x)) => (9, x as u32),
+        Some(PrepareSequence) => (10, 0),
+        Some(CommitSequence) => (11, 0),
+        Some(Chord(x)) => (12, x as u32),
+        Some(Macro(x)) => (13, x as u32),
+        Some(Unicode(x)) => (14, x as u32),
+        Some(NextUnicodeMethod) => (15, 0),
+        Some(Layer(x)) => (16, x as u32),
+        Some(ToggleLayer(x)) => (17, x as u32),
+        Some(Cancel) => (18, 0),
+    };
+    let [a, b, c, d] = u32::to_le_bytes(value);
+    [tag, a, b, c, d]
+}
+
+/// Decodes an optional action (see [`encode_action()`]).
+pub fn decode_action(bytes: [u8; ACTION]) -> Result<Option<Action>, Error> {
+    use Action::*;
+    let [tag, a, b, c, d] = bytes;
+    let value = u32::from_le_bytes([a, b, c, d]);
+    let byte = || u8::try_from(value).map_err(|_| Error::Malformed);
+    let pair = || match value.to_le_bytes() {
+        [x, y, 0, 0] => Ok((x as i8, y as i8)),
+        _ => Err(Error::Malformed),
+    };
+    let none = || if value == 0 { Ok(()) } else { Err(Error::Malformed) };
+    Ok(Some(match tag {
+        0 => return none().map(|_| None),
+        1 => Tap(byte()?),
+        2 => Press(byte()?),
+        3 => Release(byte()?),
+        4 => Toggle(byte()?),
+        5 => Consumer(u16::try_from(value).map_err(|_| Error::Malformed)?),
+        6 => pair().map(|(x, y)| Move(x, y))?,
+        7 => pair().map(|(x, y)| Scroll(x, y))?,
+        8 => Click(byte()?),
+        9 => Drag(byte()?),
+        10 => none().map(|_| PrepareSequence)?,
+        11 => none().map(|_| CommitSequence)?,
+        12 => Chord(byte()?),
+        13 => Macro(byte()?),
+        14 => Unicode(char::from_u32(value).ok_or(Error::Malformed)?),
+        15 => none().map(|_| NextUnicodeMethod)?,
+        16 => Layer(byte()?),
+        17 => ToggleLayer(byte()?),
+        18 => none().map(|_| Cancel)?,
+        _ => return Err(Error::Malformed),
+    }))
+}
+
+struct Writer {
+    report: [u8; REPORT],
+    len: usize,
+}
+
+impl Writer {
+    fn new(opcode: u8) -> Writer {
+        let mut writer = Writer { report: [0; REPORT], len: 0 };
+        writer.u8(VERSION);
+        writer.u8(opcode);
+        writer
+    }
+
+    fn u8(&mut self, x: u8) {
+        self.report[self.len] = x;
+        self.len += 1;
+    }
+
+    fn u32(&mut self, x: u32) {
+        x.to_le_bytes().into_iter().for_each(|x| self.u8(x));
+    }
+
+    fn timings(&mut self, timings: &Timings) {
+        self.u32(timings.long);
+        self.u32(timings.cancel);
+        self.u32(timings.letter);
+        self.u32(timings.word.unwrap_or(0));
+        let repeat = timings.repeat.map_or([0; 3], |x| [x.window, x.delay, x.interval]);
+        repeat.into_iter().for_each(|x| self.u32(x));
+    }
+
+    fn action(&mut self, action: Option<Action>) {
+        encode_action(action).into_iter().for_each(|x| self.u8(x));
+    }
+
+    fn list(&mut self, list: List, index: u8, offset: u8) {
+        self.u8(list as u8);
+        self.u8(index);
+        self.u8(offset);
+    }
+
+    fn actions(&mut self, actions: &[Action]) {
+        assert!(actions.len() <= LIST, "too many actions");
+        self.u8(actions.len() as u8);
+        actions.iter().for_each(|&x| self.action(Some(x)));
+    }
+}
+
+struct Reader<'a> {
+    report: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    /// Checks the version and returns the opcode.
+    fn new(report: &'a [u8]) -> Result<(Reader<'a>, u8), Error> {
+        let mut reader = Reader { report };
+        if reader.u8()? != VERSION {
+            return Err(Error::Version);
+        }
+        let opcode = reader.u8()?;
+        Ok((reader, opcode))
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        let (&x, report) = self.report.split_first().ok_or(Error::Malformed)?;
+        self.report = report;
+        Ok(x)
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes([self.u8()?, self.u8()?, self.u8()?, self.u8()?]))
+    }
+
+    fn timings(&mut self) -> Result<Timings, Error> {
+        let (long, cancel, letter) = (self.u32()?, self.u32()?, self.u32()?);
+        let word = Some(self.u32()?).filter(|&x| x != 0);
+        let repeat = Repeat { window: self.u32()?, delay: self.u32()?, interval: self.u32()? };
+        let repeat = Some(repeat).filter(|x| [x.window, x.delay, x.interval] != [0; 3]);
+        Ok(Timings { long, cancel, letter, word, repeat })
+    }
+
+    fn action(&mut self) -> Result<Option<Action>, Error> {
+        decode_action([self.u8()?, self.u8()?, self.u8()?, self.u8()?, self.u8()?])
+    }
+
+    fn list(&mut self) -> Result<(List, u8, u8), Error> {
+        let list = match self.u8()? {
+            0 => List::Chord,
+            1 => List::Macro,
+            _ => return Err(Error::Malformed),
+        };
+        Ok((list, self.u8()?, self.u8()?))
+    }
+
+    fn actions(&mut self) -> Result<Vec<Action>, Error> {
+        let len = self.u8()? as usize;
+        if len > LIST {
+            return Err(Error::Malformed);
+        }
+        (0 .. len).map(|_| self.action()?.ok_or(Error::Malformed)).collect()
+    }
+}
+
+#[test]
+fn round_trip() {
+    use alloc::vec;
+    use Action::*;
+    let actions = vec![
+        Tap(4),
+        Press(224),
+        Release(225),
+        Toggle(5),
+        Consumer(0xe9),
+        Move(-128, 127),
+        Scroll(1, -1),
+        Click(1),
+        Drag(4),
+        PrepareSequence,
+        CommitSequence,
+        Chord(3),
+        Macro(255),
+        Unicode('é'),
+        NextUnicodeMethod,
+        Layer(1),
+        ToggleLayer(2),
+        Cancel,
+    ];
+    let repeat = Some(Repeat { window: 160, delay: 240, interval: 80 });
+    let timings = Timings { long: 160, cancel: 320, letter: 160, word: Some(1000), repeat };
+    let mut requests = vec![
+        Request::Info,
+        Request::ReadTimings,
+        Request::WriteTimings(timings),
+        Request::WriteTimings(Timings { word: None, repeat: None, ..timings }),
+        Request::ReadAction { layer: 1, sequence: 254 },
+        Request::WriteAction { layer: 0, sequence: 1, action: None },
+        Request::ReadList { list: List::Macro, index: 2, offset: 11 },
+    ];
+    let mut responses = vec![
+        Response::Info { layers: 1, chords: 2, macros: 3 },
+        Response::Timings(timings),
+        Response::Action(None),
+        Response::Done,
+        Response::Error(Error::Version),
+        Response::Error(Error::Malformed),
+        Response::Error(Error::Rejected),
+    ];
+    for chunk in actions.chunks(LIST) {
+        let (list, index, offset) = (List::Chord, 0, 0);
+        requests.push(Request::WriteList { list, index, offset, actions: chunk.to_vec() });
+        responses.push(Response::List { length: 18, actions: chunk.to_vec() });
+    }
+    for &action in &actions {
+        requests.push(Request::WriteAction { layer: 3, sequence: 7, action: Some(action) });
+        responses.push(Response::Action(Some(action)));
+    }
+    for request in requests {
+        assert_eq!(Request::decode(&request.encode()), Ok(request));
+    }
+    for response in responses {
+        assert_eq!(Response::decode(&response.encode()), Ok(response));
+    }
+}
+
+#[test]
+fn malformed() {
+    let mut report = Request::WriteAction { layer: 0, sequence: 1, action: None }.encode();
+    assert_eq!(Request::decode(&report[.. 1]), Err(Error::Malformed));
+    report[0] = VERSION + 1;
+    assert_eq!(Request::decode(&report), Err(Error::Version));
+    report[0] = VERSION;
+    // Unknown action tag.
+    report[4] = 19;
+    assert_eq!(Request::decode(&report), Err(Error::Malformed));
+    // Tap with a value larger than a byte.
+    report[4] = 1;
+    report[6] = 1;
+    assert_eq!(Request::decode(&report), Err(Error::Malformed));
+    // Invalid code point.
+    report[4] = 14;
+    report[5 .. 9].copy_from_slice(&0xd800u32.to_le_bytes());
+    assert_eq!(Request::decode(&report), Err(Error::Malformed));
+}
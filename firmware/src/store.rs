@@ -0,0 +1,521 @@
+// Copyright 2021-2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Stores settings in flash.
+//!
+//! Settings are appended as records to a ring of pages, such that a page is only erased when the
+//! previous one is full (wear leveling). Each record starts with a header holding a sequence
+//! number, a format version (for migrations), and a CRC. The valid record with the highest
+//! sequence number is loaded. Without valid record, the compiled-in defaults apply.
+
+use alloc::vec::Vec;
+use defmt::Format;
+
+use crate::protocol::{decode_action, encode_action, ACTION};
+use crate::{Action, Duration, Keymap, Repeat, State};
+
+/// Write granularity of the flash in bytes (offsets and lengths are multiples of it).
+pub const ALIGN: usize = 8;
+
+/// Flash region made of pages.
+///
+/// Erased bytes read as `0xff` and writes may only clear bits.
+pub trait Flash {
+    /// Size of a page in bytes (the erase unit).
+    const PAGE: usize;
+
+    /// Returns the number of pages (at least 2).
+    fn pages(&self) -> usize;
+
+    /// Reads bytes at an offset.
+    fn read(&self, offset: usize, data: &mut [u8]);
+
+    /// Writes bytes at an offset (both aligned to [`ALIGN`]) within a page.
+    ///
+    /// Returns [`Error::Write`] if the flash reports a programming error.
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), Error>;
+
+    /// Erases a page.
+    ///
+    /// Returns [`Error::Erase`] if the flash reports an erase error.
+    fn erase(&mut self, page: usize) -> Result<(), Error>;
+}
+
+/// Settings changed at runtime (the timings are those of [`Config`](crate::Config)).
+#[derive(Clone, PartialEq, Eq)]
+pub struct Settings {
+    pub long: Duration,
+    pub cancel: Duration,
+    pub letter: Duration,
+    pub word: Option<Duration>,
+    pub repeat: Option<Repeat>,
+    pub keymap: Keymap,
+}
+
+/// Errors when saving settings.
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The settings don't fit in a page.
+    TooLarge,
+
+    /// The record could not be written (or read back after writing).
+    Write,
+
+    /// The page could not be erased.
+    Erase,
+}
+
+/// Format version of the records.
+const VERSION: u16 = 1;
+
+/// Marks the start of a record.
+const MAGIC: u32 = 0x6b62_6b6f;
+
+/// Size of a record header (magic, sequence, version, length, and CRC).
+const HEADER: usize = 16;
+
+#[derive(Clone, Copy)]
+struct Header {
+    sequence: u32,
+    version: u16,
+    length: u16,
+}
+
+/// Settings stored in a flash region.
+pub struct Store<F: Flash> {
+    flash: F,
+    /// Page of the next record.
+    page: usize,
+    /// Offset of the next record within its page.
+    offset: usize,
+    /// Latest valid record (its header and offset), if any.
+    latest: Option<(Header, usize)>,
+}
+
+impl Settings {
+    /// Returns the current settings of a state.
+    pub fn new(state: &State) -> Settings {
+        let config = state.config();
+        let (long, cancel, letter, word) = (config.long, config.cancel, config.letter, config.word);
+        let keymap = state.keymap().clone();
+        Settings { long, cancel, letter, word, repeat: config.repeat, keymap }
+    }
+
+    /// Applies the settings to a state.
+    pub fn apply(self, state: &mut State) {
+        let mut config = state.config();
+        config.long = self.long;
+        config.cancel = self.cancel;
+        config.letter = self.letter;
+        config.word = self.word;
+        config.repeat = self.repeat;
+        state.set_config(config);
+        state.set_keymap(self.keymap);
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        let word = self.word.map_or(0, |x| x.millis());
+        let repeat =
+            self.repeat.map_or([0; 3], |x| [x.window, x.delay, x.interval].map(|x| x.millis()));
+        let durations = [self.long, self.cancel, self.letter].map(|x| x.millis());
+        for x in durations.into_iter().chain([word]).chain(repeat) {
+            data.extend_from_slice(&x.to_le_bytes());
+        }
+        let keymap = &self.keymap;
+        let actions = |data: &mut Vec<u8>, actions: &[Action]| {
+            data.extend_from_slice(&(actions.len() as u16).to_le_bytes());
+            for &action in actions {
+                data.extend_from_slice(&encode_action(Some(action.into())));
+            }
+        };
+        actions(&mut data, &[keymap.word()]);
+        data.extend_from_slice(&(keymap.chords() as u16).to_le_bytes());
+        (0 .. keymap.chords()).for_each(|x| actions(&mut data, keymap.chord(x as u8)));
+        data.extend_from_slice(&(keymap.macros() as u16).to_le_bytes());
+        (0 .. keymap.macros()).for_each(|x| actions(&mut data, keymap.macro_(x as u8)));
+        data.extend_from_slice(&(keymap.layers() as u16).to_le_bytes());
+        for layer in 0 .. keymap.layers() as u8 {
+            let mapped: Vec<u8> = (1 .. crate::keymap::LENGTH as u8)
+                .filter(|&x| keymap.get(layer, x as usize).is_some())
+                .collect();
+            data.extend_from_slice(&(mapped.len() as u16).to_le_bytes());
+            for sequence in mapped {
+                data.push(sequence);
+                let action = keymap.get(layer, sequence as usize).map(Into::into);
+                data.extend_from_slice(&encode_action(action));
+            }
+        }
+        data
+    }
+
+    /// Decodes settings of a given format version.
+    fn decode(version: u16, data: &[u8]) -> Option<Settings> {
+        match version {
+            // Older versions would be migrated here.
+            VERSION => Settings::decode_v1(&mut Decoder { data }),
+            _ => None,
+        }
+    }
+
+    fn decode_v1(data: &mut Decoder) -> Option<Settings> {
+        let mut duration = || data.u32().map(Duration::from_millis);
+        let (long, cancel, letter) = (duration()?, duration()?, duration()?);
+        let word = Some(duration()?).filter(|x| x.millis() != 0);
+        let repeat = Repeat { window: duration()?, delay: duration()?, interval: duration()? };
+        let repeat =
+            Some(repeat).filter(|x| [x.window, x.delay, x.interval].map(|x| x.millis()) != [0; 3]);
+        let [word_action] = data.actions()?[..] else { return None };
+        // Chords and macros are created empty first, because they may refer to each other. Their
+        // actions are then set in order, which rejects stored keymaps with cycles.
+        let chords: Vec<Vec<Action>> =
+            (0 .. data.u16()?).map(|_| data.actions()).collect::<Option<_>>()?;
+        let macros: Vec<Vec<Action>> =
+            (0 .. data.u16()?).map(|_| data.actions()).collect::<Option<_>>()?;
+        let layers = data.u16()?;
+        let mut builder = Keymap::builder();
+        builder.layer(u8::try_from(layers.checked_sub(1)?).ok()?);
+        let mut keymap = builder.build();
+        for i in 0 .. chords.len() {
+            keymap.set_chord(u8::try_from(i).ok()?, 0, &[]).ok()?;
+        }
+        for i in 0 .. macros.len() {
+            keymap.set_macro(u8::try_from(i).ok()?, 0, &[]).ok()?;
+        }
+        for (i, actions) in chords.iter().enumerate() {
+            keymap.set_chord(i as u8, 0, actions).ok()?;
+        }
+        for (i, actions) in macros.iter().enumerate() {
+            keymap.set_macro(i as u8, 0, actions).ok()?;
+        }
+        for layer in 0 .. layers as u8 {
+            for _ in 0 .. data.u16()? {
+                let sequence = data.u8()? as usize;
+                let action = data.action()?;
+                keymap.set(layer, sequence, Some(action)).ok()?;
+            }
+        }
+        keymap.set_word(word_action).ok()?;
+        data.data.is_empty().then_some(Settings { long, cancel, letter, word, repeat, keymap })
+    }
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+}
+
+impl Decoder<'_> {
+    fn bytes<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let bytes = self.data.get(.. N)?.try_into().ok()?;
+        self.data = &self.data[N ..];
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes().map(|[x]| x)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes().map(u16::from_le_bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes().map(u32::from_le_bytes)
+    }
+
+    fn action(&mut self) -> Option<Action> {
+        let action = decode_action(self.bytes::<ACTION>()?).ok()??;
+        Some(action.into())
+    }
+
+    fn actions(&mut self) -> Option<Vec<Action>> {
+        (0 .. self.u16()?).map(|_| self.action()).collect()
+    }
+}
+
+impl<F: Flash> Store<F> {
+    /// Scans the flash for the latest valid record.
+    pub fn new(flash: F) -> Store<F> {
+        assert!(flash.pages() >= 2 && F::PAGE % ALIGN == 0);
+        let mut store = Store { flash, page: 0, offset: 0, latest: None };
+        for page in 0 .. store.flash.pages() {
+            let mut offset = 0;
+            while let Some(header) = store.record(page, offset) {
+                let size = size(header.length);
+                if store.latest.is_none_or(|(latest, _)| header.sequence > latest.sequence) {
+                    store.latest = Some((header, page * F::PAGE + offset));
+                    (store.page, store.offset) = (page, offset + size);
+                }
+                offset += size;
+            }
+        }
+        store
+    }
+
+    /// Loads the latest valid settings, if any.
+    pub fn load(&self) -> Option<Settings> {
+        let (header, offset) = self.latest?;
+        let mut data = alloc::vec![0; header.length as usize];
+        self.flash.read(offset + HEADER, &mut data);
+        let settings = Settings::decode(header.version, &data);
+        if settings.is_none() {
+            defmt::warn!("Invalid settings (version {})", header.version);
+        }
+        settings
+    }
+
+    /// Appends settings, erasing the next page if needed.
+    pub fn save(&mut self, settings: &Settings) -> Result<(), Error> {
+        let data = settings.encode();
+        let length = u16::try_from(data.len()).map_err(|_| Error::TooLarge)?;
+        let size = size(length);
+        if size > F::PAGE {
+            return Err(Error::TooLarge);
+        }
+        if self.offset + size > F::PAGE || !self.is_erased(size) {
+            self.page = (self.page + 1) % self.flash.pages();
+            self.offset = 0;
+            self.flash.erase(self.page)?;
+        }
+        let sequence = self.latest.map_or(0, |(x, _)| x.sequence.wrapping_add(1));
+        let header = Header { sequence, version: VERSION, length };
+        let mut record = Vec::with_capacity(size);
+        record.extend_from_slice(&MAGIC.to_le_bytes());
+        record.extend_from_slice(&header.sequence.to_le_bytes());
+        record.extend_from_slice(&header.version.to_le_bytes());
+        record.extend_from_slice(&header.length.to_le_bytes());
+        let crc = crc32(&[&record[4 ..], &data]);
+        record.extend_from_slice(&crc.to_le_bytes());
+        record.extend_from_slice(&data);
+        record.resize(size, 0xff);
+        self.flash.write(self.page * F::PAGE + self.offset, &record)?;
+        if self.record(self.page, self.offset).is_none() {
+            return Err(Error::Write);
+        }
+        self.latest = Some((header, self.page * F::PAGE + self.offset));
+        self.offset += size;
+        Ok(())
+    }
+
+    /// Returns the header of a valid record at an offset within a page.
+    fn record(&self, page: usize, offset: usize) -> Option<Header> {
+        if offset + HEADER > F::PAGE {
+            return None;
+        }
+        let start = page * F::PAGE + offset;
+        let mut header = [0; HEADER];
+        self.flash.read(start, &mut header);
+        let word = |i: usize| u32::from_le_bytes(header[i .. i + 4].try_into().unwrap());
+        if word(0) != MAGIC {
+            return None;
+        }
+        let half = |i: usize| u16::from_le_bytes(header[i .. i + 2].try_into().unwrap());
+        let header_ = Header { sequence: word(4), version: half(8), length: half(10) };
+        if offset + size(header_.length) > F::PAGE {
+            return None;
+        }
+        let mut data = alloc::vec![0; header_.length as usize];
+        self.flash.read(start + HEADER, &mut data);
+        (crc32(&[&header[4 .. 12], &data]) == word(12)).then_some(header_)
+    }
+
+    /// Returns whether the next bytes are erased.
+    fn is_erased(&self, size: usize) -> bool {
+        let mut data = alloc::vec![0; size];
+        self.flash.read(self.page * F::PAGE + self.offset, &mut data);
+        data.iter().all(|&x| x == 0xff)
+    }
+}
+
+/// Returns the aligned size of a record.
+fn size(length: u16) -> usize {
+    (HEADER + length as usize).next_multiple_of(ALIGN)
+}
+
+/// Computes the CRC-32 (as in Ethernet) of a sequence of slices.
+fn crc32(data: &[&[u8]]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data.iter().flat_map(|x| x.iter()) {
+        crc ^= byte as u32;
+        for _ in 0 .. 8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// In-memory flash checking that writes only clear erased bytes.
+#[cfg(test)]
+struct Mock {
+    data: Vec<u8>,
+    erases: Vec<usize>,
+    /// Whether writes and erases fail (without effect).
+    failing: bool,
+}
+
+#[cfg(test)]
+impl Mock {
+    fn new(pages: usize) -> Mock {
+        let (data, erases) = (alloc::vec![0xff; pages * Mock::PAGE], alloc::vec![0; pages]);
+        Mock { data, erases, failing: false }
+    }
+}
+
+#[cfg(test)]
+impl Flash for &mut Mock {
+    const PAGE: usize = 256;
+
+    fn pages(&self) -> usize {
+        self.erases.len()
+    }
+
+    fn read(&self, offset: usize, data: &mut [u8]) {
+        data.copy_from_slice(&self.data[offset .. offset + data.len()]);
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), Error> {
+        assert_eq!(offset % ALIGN, 0);
+        assert_eq!(data.len() % ALIGN, 0);
+        assert_eq!(offset / Self::PAGE, (offset + data.len() - 1) / Self::PAGE);
+        let target = &mut self.data[offset .. offset + data.len()];
+        assert!(target.iter().all(|&x| x == 0xff));
+        if self.failing {
+            return Err(Error::Write);
+        }
+        target.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn erase(&mut self, page: usize) -> Result<(), Error> {
+        if self.failing {
+            return Err(Error::Erase);
+        }
+        self.data[page * Self::PAGE ..][.. Self::PAGE].fill(0xff);
+        self.erases[page] += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Mock {
+    const PAGE: usize = <&mut Mock as Flash>::PAGE;
+}
+
+#[cfg(test)]
+fn test_settings(letter: u32) -> Settings {
+    use crate::Action::*;
+    let mut keymap = Keymap::builder();
+    keymap.map(".-", Tap(4)).unwrap();
+    keymap.chord("-...", &[Press(0xe1), Tap(5)]).unwrap();
+    keymap.macro_("-.-.", &[Tap(6), Chord(0)]).unwrap();
+    keymap.layer(1).map(".", Unicode('é')).unwrap();
+    keymap.word(Tap(0x2c)).unwrap();
+    let (long, cancel, letter) = (crate::ms(100), crate::ms(250), crate::ms(letter));
+    let repeat =
+        Some(Repeat { window: crate::ms(100), delay: crate::ms(200), interval: crate::ms(50) });
+    let (word, keymap) = (Some(crate::ms(700)), keymap.build());
+    Settings { long, cancel, letter, word, repeat, keymap }
+}
+
+#[test]
+fn save_load() {
+    let mut flash = Mock::new(2);
+    assert!(Store::new(&mut flash).load().is_none());
+    let settings = test_settings(100);
+    Store::new(&mut flash).save(&settings).unwrap();
+    assert!(Store::new(&mut flash).load() == Some(settings.clone()));
+    let mut state = crate::test_state(Keymap::default());
+    settings.clone().apply(&mut state);
+    assert!(Settings::new(&state) == settings);
+    let unmapped =
+        Settings { word: None, repeat: None, keymap: Keymap::builder().build(), ..settings };
+    Store::new(&mut flash).save(&unmapped).unwrap();
+    assert!(Store::new(&mut flash).load() == Some(unmapped));
+}
+
+#[test]
+fn wear_leveling() {
+    let mut flash = Mock::new(3);
+    let mut store = Store::new(&mut flash);
+    for letter in 1 .. 40 {
+        store.save(&test_settings(letter)).unwrap();
+        assert!(store.load() == Some(test_settings(letter)));
+    }
+    assert!(Store::new(&mut flash).load() == Some(test_settings(39)));
+    let erases = flash.erases.clone();
+    assert!(erases.iter().all(|&x| x > 1), "{erases:?}");
+    assert!(erases.iter().max().unwrap() - erases.iter().min().unwrap() <= 1, "{erases:?}");
+}
+
+#[test]
+fn corruption() {
+    let mut flash = Mock::new(2);
+    let mut store = Store::new(&mut flash);
+    store.save(&test_settings(100)).unwrap();
+    store.save(&test_settings(110)).unwrap();
+    let second = size(test_settings(100).encode().len() as u16) + HEADER;
+    // Clearing bits of the latest record falls back to the previous one.
+    flash.data[second] = 0;
+    assert!(Store::new(&mut flash).load() == Some(test_settings(100)));
+    // The next record skips the corrupted region.
+    Store::new(&mut flash).save(&test_settings(120)).unwrap();
+    assert!(Store::new(&mut flash).load() == Some(test_settings(120)));
+    assert_eq!(flash.erases, [0, 1]);
+    // Without valid record, there are no settings.
+    flash.data[Mock::PAGE + HEADER] = 0;
+    flash.data[HEADER] = 0;
+    assert!(Store::new(&mut flash).load().is_none());
+}
+
+#[test]
+fn interrupted_write() {
+    let mut flash = Mock::new(2);
+    Store::new(&mut flash).save(&test_settings(100)).unwrap();
+    let end = size(test_settings(100).encode().len() as u16);
+    // Only the first half of the next record was written.
+    flash.data.copy_within(.. 24, end);
+    assert!(Store::new(&mut flash).load() == Some(test_settings(100)));
+    Store::new(&mut flash).save(&test_settings(110)).unwrap();
+    assert!(Store::new(&mut flash).load() == Some(test_settings(110)));
+}
+
+#[test]
+fn flash_errors() {
+    let mut flash = Mock::new(2);
+    Store::new(&mut flash).save(&test_settings(100)).unwrap();
+    flash.failing = true;
+    assert_eq!(Store::new(&mut flash).save(&test_settings(110)), Err(Error::Write));
+    flash.failing = false;
+    assert!(Store::new(&mut flash).load() == Some(test_settings(100)));
+    // The next record needs to erase the other page.
+    let end = size(test_settings(100).encode().len() as u16);
+    flash.data[end .. Mock::PAGE].fill(0);
+    flash.failing = true;
+    assert_eq!(Store::new(&mut flash).save(&test_settings(110)), Err(Error::Erase));
+    flash.failing = false;
+    Store::new(&mut flash).save(&test_settings(110)).unwrap();
+    assert!(Store::new(&mut flash).load() == Some(test_settings(110)));
+}
+
+#[test]
+fn unknown_version() {
+    let mut flash = Mock::new(2);
+    let mut store = Store::new(&mut flash);
+    store.save(&test_settings(100)).unwrap();
+    let (mut header, offset) = store.latest.unwrap();
+    header.version = VERSION + 1;
+    store.latest = Some((header, offset));
+    assert!(store.load().is_none());
+    assert!(Settings::decode(VERSION + 1, &test_settings(100).encode()).is_none());
+}
@@ -0,0 +1,131 @@
+// Copyright 2021-2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use defmt::Format;
+
+/// Timer of a board.
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Clock {
+    /// Number of ticks per second.
+    pub frequency: u32,
+
+    /// Maximum tick count (the count wraps back to 0 after this value).
+    pub maximum: usize,
+}
+
+/// Point in time, as a tick count of the board timer.
+#[derive(Format, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Instant {
+    ticks: usize,
+}
+
+/// Duration independent of the board timer.
+#[derive(Format, Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Duration {
+    millis: u32,
+}
+
+impl Clock {
+    /// Returns the number of ticks of a duration (rounded down).
+    pub fn ticks(&self, duration: Duration) -> usize {
+        (duration.millis as u64 * self.frequency as u64 / 1000) as usize
+    }
+
+    /// Returns the duration of a number of ticks (rounded down and saturated).
+    pub fn duration(&self, ticks: usize) -> Duration {
+        let millis = (ticks as u64).saturating_mul(1000) / self.frequency as u64;
+        Duration::from_millis(core::cmp::min(millis, u32::MAX as u64) as u32)
+    }
+
+    /// Returns the number of ticks from an instant to a later one.
+    ///
+    /// The later instant may have wrapped around, but not more than once.
+    pub fn elapsed(&self, since: Instant, now: Instant) -> usize {
+        if now.ticks < since.ticks {
+            self.maximum - since.ticks + now.ticks + 1
+        } else {
+            now.ticks - since.ticks
+        }
+    }
+
+    /// Returns the instant some ticks after another, wrapping around if needed.
+    pub fn add(&self, instant: Instant, ticks: usize) -> Instant {
+        let ticks = ticks % self.period();
+        match self.maximum - instant.ticks {
+            left if ticks <= left => Instant::from_ticks(instant.ticks + ticks),
+            left => Instant::from_ticks(ticks - left - 1),
+        }
+    }
+
+    /// Returns the number of ticks before the count wraps around (saturating).
+    fn period(&self) -> usize {
+        self.maximum.saturating_add(1)
+    }
+}
+
+impl Instant {
+    pub const fn from_ticks(ticks: usize) -> Instant {
+        Instant { ticks }
+    }
+
+    pub fn ticks(&self) -> usize {
+        self.ticks
+    }
+}
+
+impl Duration {
+    pub const fn from_millis(millis: u32) -> Duration {
+        Duration { millis }
+    }
+
+    pub fn millis(&self) -> u32 {
+        self.millis
+    }
+}
+
+#[test]
+fn ticks() {
+    let clock = Clock { frequency: 32768, maximum: 0xffffff };
+    assert_eq!(clock.ticks(Duration::from_millis(1000)), 32768);
+    assert_eq!(clock.ticks(Duration::from_millis(5)), 163);
+    assert_eq!(clock.duration(163), Duration::from_millis(4));
+    assert_eq!(clock.duration(164), Duration::from_millis(5));
+    let clock = Clock { frequency: 8_000_000, maximum: u32::MAX as usize };
+    assert_eq!(clock.ticks(Duration::from_millis(60_000)), 480_000_000);
+}
+
+#[test]
+fn elapsed_add() {
+    // Small xorshift generator for reproducible pseudo-random inputs.
+    let mut seed = 0x2545f4914f6cdd1du64;
+    let mut random = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed as usize
+    };
+    for maximum in [999, 0xffffff, u32::MAX as usize, usize::MAX] {
+        let clock = Clock { frequency: 1000, maximum };
+        for _ in 0 .. 1000 {
+            let since = Instant::from_ticks(random() % clock.period());
+            let ticks = random() % clock.period();
+            let now = clock.add(since, ticks);
+            assert!(now.ticks() <= maximum);
+            assert_eq!(clock.elapsed(since, now), ticks, "{:?} {}", since, ticks);
+        }
+        let last = Instant::from_ticks(maximum);
+        assert_eq!(clock.add(last, 1), Instant::from_ticks(0));
+        assert_eq!(clock.elapsed(last, Instant::from_ticks(0)), 1);
+    }
+}
@@ -0,0 +1,146 @@
+// Copyright 2021-2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Types Unicode code points using host-specific input methods.
+
+use alloc::vec::Vec;
+use defmt::Format;
+
+use crate::Action;
+
+const LCTRL: u8 = 224;
+const LSHIFT: u8 = 225;
+const LALT: u8 = 226;
+const SPACE: u8 = 44;
+const KEYPAD_PLUS: u8 = 87;
+
+/// How the host inputs Unicode code points.
+#[derive(Format, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    /// Ctrl+Shift+U followed by the hexadecimal code point and Space (Linux with IBus).
+    #[default]
+    Linux,
+
+    /// Alt held while typing Keypad+ and the hexadecimal code point (Windows).
+    ///
+    /// The host needs the `EnableHexNumpad` registry setting.
+    Windows,
+
+    /// Option held while typing the hexadecimal UTF-16 code units (macOS).
+    ///
+    /// The host needs the Unicode Hex Input source.
+    MacOs,
+}
+
+impl Method {
+    /// Returns the next method (wrapping around).
+    pub fn next(self) -> Method {
+        match self {
+            Method::Linux => Method::Windows,
+            Method::Windows => Method::MacOs,
+            Method::MacOs => Method::Linux,
+        }
+    }
+}
+
+/// Returns the keycode of an hexadecimal digit.
+fn digit(x: u32, keypad: bool) -> u8 {
+    match x {
+        0 if keypad => 98,
+        1 ..= 9 if keypad => 88 + x as u8,
+        0 => 39,
+        1 ..= 9 => 29 + x as u8,
+        _ => 4 + (x - 10) as u8,
+    }
+}
+
+/// Pushes the taps of the hexadecimal digits of a number.
+fn hex(actions: &mut Vec<Action>, x: u32, width: usize, keypad: bool) {
+    let len = core::cmp::max(width, (8 - x.leading_zeros() as usize / 4).max(1));
+    for i in (0 .. len).rev() {
+        actions.push(Action::Tap(digit(x >> (4 * i) & 0xf, keypad)));
+    }
+}
+
+/// Returns the actions typing a code point.
+///
+/// The actions must be run outside sequences.
+pub fn actions(method: Method, x: char) -> Vec<Action> {
+    let mut actions = Vec::new();
+    match method {
+        Method::Linux => {
+            actions.extend_from_slice(&[Action::Tap(LCTRL), Action::Tap(LSHIFT), Action::Tap(24)]);
+            hex(&mut actions, x as u32, 1, false);
+            actions.push(Action::Tap(SPACE));
+        }
+        Method::Windows => {
+            actions.extend_from_slice(&[Action::Press(LALT), Action::Tap(KEYPAD_PLUS)]);
+            hex(&mut actions, x as u32, 1, true);
+            actions.push(Action::Release(LALT));
+        }
+        Method::MacOs => {
+            actions.push(Action::Press(LALT));
+            for unit in x.encode_utf16(&mut [0; 2]) {
+                hex(&mut actions, *unit as u32, 4, false);
+            }
+            actions.push(Action::Release(LALT));
+        }
+    }
+    actions
+}
+
+#[test]
+fn linux() {
+    use Action::*;
+    let expected = [Tap(LCTRL), Tap(LSHIFT), Tap(24), Tap(31), Tap(39), Tap(4), Tap(6), Tap(SPACE)];
+    assert_eq!(actions(Method::Linux, '€'), expected);
+    let expected = [Tap(LCTRL), Tap(LSHIFT), Tap(24), Tap(8), Tap(38), Tap(SPACE)];
+    assert_eq!(actions(Method::Linux, 'é'), expected);
+}
+
+#[test]
+fn windows() {
+    use Action::*;
+    let expected = [Press(LALT), Tap(KEYPAD_PLUS), Tap(8), Tap(97), Release(LALT)];
+    assert_eq!(actions(Method::Windows, 'é'), expected);
+    let expected = [Press(LALT), Tap(KEYPAD_PLUS), Tap(98), Release(LALT)];
+    assert_eq!(actions(Method::Windows, '\0'), expected);
+}
+
+#[test]
+fn mac_os() {
+    use Action::*;
+    let expected = [Press(LALT), Tap(39), Tap(39), Tap(8), Tap(38), Release(LALT)];
+    assert_eq!(actions(Method::MacOs, 'é'), expected);
+    // U+1F600 is D83D DE00 in UTF-16.
+    let expected = [
+        Press(LALT),
+        Tap(7),
+        Tap(37),
+        Tap(32),
+        Tap(7),
+        Tap(7),
+        Tap(8),
+        Tap(39),
+        Tap(39),
+        Release(LALT),
+    ];
+    assert_eq!(actions(Method::MacOs, '😀'), expected);
+}
+
+#[test]
+fn cycle() {
+    assert_eq!(Method::default(), Method::Linux);
+    assert_eq!(Method::Linux.next().next().next(), Method::Linux);
+}
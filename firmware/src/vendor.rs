@@ -0,0 +1,283 @@
+// Copyright 2021-2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Serves the vendor HID configuration channel (see [`protocol`]).
+
+use alloc::vec::Vec;
+
+use crate::protocol::{self, Error, List, Request, Response, Timings};
+use crate::{Action, Duration, Repeat, State};
+
+/// Handles a request report and returns the response report.
+pub fn handle(state: &mut State, report: &[u8]) -> [u8; protocol::REPORT] {
+    let response = match Request::decode(report) {
+        Ok(request) => serve(state, request),
+        Err(error) => Response::Error(error),
+    };
+    response.encode()
+}
+
+fn serve(state: &mut State, request: Request) -> Response {
+    let keymap = state.keymap();
+    let count = |x: usize| u8::try_from(x).unwrap_or(u8::MAX);
+    match request {
+        Request::Info => Response::Info {
+            layers: count(keymap.layers()),
+            chords: count(keymap.chords()),
+            macros: count(keymap.macros()),
+        },
+        Request::ReadTimings => {
+            let config = state.config();
+            Response::Timings(Timings {
+                long: config.long.millis(),
+                cancel: config.cancel.millis(),
+                letter: config.letter.millis(),
+                word: config.word.map(|x| x.millis()),
+                repeat: config.repeat.map(Into::into),
+            })
+        }
+        Request::WriteTimings(timings) => {
+            let mut config = state.config();
+            config.long = Duration::from_millis(timings.long);
+            config.cancel = Duration::from_millis(timings.cancel);
+            config.letter = Duration::from_millis(timings.letter);
+            config.word = timings.word.map(Duration::from_millis);
+            config.repeat = timings.repeat.map(Into::into);
+            if !config.is_valid() {
+                return Response::Error(Error::Rejected);
+            }
+            state.set_config(config);
+            Response::Done
+        }
+        Request::ReadAction { sequence: 0, .. } => Response::Action(Some(keymap.word().into())),
+        Request::ReadAction { layer, sequence } => {
+            Response::Action(keymap.get(layer, sequence as usize).map(Into::into))
+        }
+        Request::WriteAction { layer, sequence, action } => {
+            let mut keymap = keymap.clone();
+            let action = action.map(Action::from);
+            let result = match (sequence, action) {
+                (0, Some(action)) => keymap.set_word(action),
+                (0, None) => return Response::Error(Error::Rejected),
+                (sequence, action) => keymap.set(layer, sequence as usize, action),
+            };
+            write(state, keymap, result)
+        }
+        Request::ReadList { list, index, offset } => {
+            let actions = match list {
+                List::Chord if (index as usize) < keymap.chords() => keymap.chord(index),
+                List::Macro if (index as usize) < keymap.macros() => keymap.macro_(index),
+                _ => return Response::Error(Error::Rejected),
+            };
+            let length = count(actions.len());
+            match actions.get(offset as usize ..) {
+                Some(actions) => {
+                    let actions = actions.iter().take(protocol::LIST).map(|&x| x.into()).collect();
+                    Response::List { length, actions }
+                }
+                None => Response::Error(Error::Rejected),
+            }
+        }
+        Request::WriteList { list, index, offset, actions } => {
+            let mut keymap = keymap.clone();
+            let actions: Vec<Action> = actions.into_iter().map(Action::from).collect();
+            let result = match list {
+                List::Chord => keymap.set_chord(index, offset as usize, &actions),
+                List::Macro => keymap.set_macro(index, offset as usize, &actions),
+            };
+            write(state, keymap, result)
+        }
+    }
+}
+
+/// Sets the keymap if it was successfully modified.
+fn write(
+    state: &mut State,
+    keymap: crate::Keymap,
+    result: Result<(), crate::keymap::Error>,
+) -> Response {
+    match result {
+        Ok(()) => {
+            state.set_keymap(keymap);
+            Response::Done
+        }
+        Err(error) => {
+            defmt::warn!("Rejected keymap write: {:?}", error);
+            Response::Error(Error::Rejected)
+        }
+    }
+}
+
+impl From<Action> for protocol::Action {
+    fn from(action: Action) -> protocol::Action {
+        use protocol::Action as P;
+        match action {
+            Action::Tap(x) => P::Tap(x),
+            Action::Press(x) => P::Press(x),
+            Action::Release(x) => P::Release(x),
+            Action::Toggle(x) => P::Toggle(x),
+            Action::Consumer(x) => P::Consumer(x),
+            Action::Move(x, y) => P::Move(x, y),
+            Action::Scroll(x, y) => P::Scroll(x, y),
+            Action::Click(x) => P::Click(x),
+            Action::Drag(x) => P::Drag(x),
+            Action::PrepareSequence => P::PrepareSequence,
+            Action::CommitSequence => P::CommitSequence,
+            Action::Chord(x) => P::Chord(x),
+            Action::Macro(x) => P::Macro(x),
+            Action::Unicode(x) => P::Unicode(x),
+            Action::NextUnicodeMethod => P::NextUnicodeMethod,
+            Action::Layer(x) => P::Layer(x),
+            Action::ToggleLayer(x) => P::ToggleLayer(x),
+            Action::Cancel => P::Cancel,
+        }
+    }
+}
+
+impl From<protocol::Action> for Action {
+    fn from(action: protocol::Action) -> Action {
+        use protocol::Action as P;
+        match action {
+            P::Tap(x) => Action::Tap(x),
+            P::Press(x) => Action::Press(x),
+            P::Release(x) => Action::Release(x),
+            P::Toggle(x) => Action::Toggle(x),
+            P::Consumer(x) => Action::Consumer(x),
+            P::Move(x, y) => Action::Move(x, y),
+            P::Scroll(x, y) => Action::Scroll(x, y),
+            P::Click(x) => Action::Click(x),
+            P::Drag(x) => Action::Drag(x),
+            P::PrepareSequence => Action::PrepareSequence,
+            P::CommitSequence => Action::CommitSequence,
+            P::Chord(x) => Action::Chord(x),
+            P::Macro(x) => Action::Macro(x),
+            P::Unicode(x) => Action::Unicode(x),
+            P::NextUnicodeMethod => Action::NextUnicodeMethod,
+            P::Layer(x) => Action::Layer(x),
+            P::ToggleLayer(x) => Action::ToggleLayer(x),
+            P::Cancel => Action::Cancel,
+        }
+    }
+}
+
+impl From<Repeat> for protocol::Repeat {
+    fn from(repeat: Repeat) -> protocol::Repeat {
+        let Repeat { window, delay, interval } = repeat;
+        let (window, delay, interval) = (window.millis(), delay.millis(), interval.millis());
+        protocol::Repeat { window, delay, interval }
+    }
+}
+
+impl From<protocol::Repeat> for Repeat {
+    fn from(repeat: protocol::Repeat) -> Repeat {
+        let protocol::Repeat { window, delay, interval } = repeat;
+        let ms = Duration::from_millis;
+        Repeat { window: ms(window), delay: ms(delay), interval: ms(interval) }
+    }
+}
+
+/// Sends a request as the host tool would and returns the decoded response.
+#[cfg(test)]
+fn request(state: &mut State, request: Request) -> Response {
+    Response::decode(&handle(state, &request.encode())).unwrap()
+}
+
+#[test]
+fn timings() {
+    let mut state = State::new(crate::test_config());
+    let timings = Timings { long: 100, cancel: 250, letter: 100, word: None, repeat: None };
+    assert_eq!(request(&mut state, Request::ReadTimings), Response::Timings(timings));
+    let repeat = Some(protocol::Repeat { window: 100, delay: 200, interval: 50 });
+    let timings = Timings { long: 120, cancel: 300, letter: 140, word: Some(700), repeat };
+    assert_eq!(request(&mut state, Request::WriteTimings(timings)), Response::Done);
+    assert_eq!(request(&mut state, Request::ReadTimings), Response::Timings(timings));
+    assert_eq!(state.config().word, Some(Duration::from_millis(700)));
+    assert_eq!(state.config().repeat.map(|x| x.interval), Some(Duration::from_millis(50)));
+    let zero = Timings { long: 0, ..timings };
+    assert_eq!(request(&mut state, Request::WriteTimings(zero)), Response::Error(Error::Rejected));
+    let repeat = Some(protocol::Repeat { window: 100, delay: 200, interval: 0 });
+    let zero = Timings { repeat, ..timings };
+    assert_eq!(request(&mut state, Request::WriteTimings(zero)), Response::Error(Error::Rejected));
+    let short = Timings { cancel: 120, ..timings };
+    assert_eq!(request(&mut state, Request::WriteTimings(short)), Response::Error(Error::Rejected));
+    let short = Timings { word: Some(140), ..timings };
+    assert_eq!(request(&mut state, Request::WriteTimings(short)), Response::Error(Error::Rejected));
+    assert_eq!(request(&mut state, Request::ReadTimings), Response::Timings(timings));
+}
+
+#[test]
+fn keymap() {
+    use protocol::Action::*;
+    let mut state = State::new(crate::test_config());
+    state.set_keymap(crate::Keymap::builder().build());
+    let info = |layers, chords, macros| Response::Info { layers, chords, macros };
+    assert_eq!(request(&mut state, Request::Info), info(1, 0, 0));
+    let write = |layer, sequence, action| Request::WriteAction { layer, sequence, action };
+    assert_eq!(request(&mut state, write(1, 4, Some(Tap(4)))), Response::Done);
+    assert_eq!(request(&mut state, write(0, 0, Some(Tap(40)))), Response::Done);
+    let rejected = Response::Error(Error::Rejected);
+    assert_eq!(request(&mut state, write(3, 4, Some(Tap(4)))), rejected);
+    assert_eq!(request(&mut state, write(0, 1, Some(Macro(0)))), rejected);
+    let read = |layer, sequence| Request::ReadAction { layer, sequence };
+    assert_eq!(request(&mut state, read(1, 4)), Response::Action(Some(Tap(4))));
+    assert_eq!(request(&mut state, read(0, 4)), Response::Action(None));
+    assert_eq!(request(&mut state, read(0, 0)), Response::Action(Some(Tap(40))));
+    // Long macros are written and read in multiple requests.
+    let text: Vec<_> = "hello world".chars().map(Unicode).chain([Cancel, Tap(40)]).collect();
+    let (head, tail) = text.split_at(protocol::LIST);
+    let write = |offset, actions: &[_]| {
+        let actions = actions.to_vec();
+        Request::WriteList { list: List::Macro, index: 0, offset, actions }
+    };
+    assert_eq!(request(&mut state, write(0, head)), Response::Done);
+    assert_eq!(request(&mut state, write(protocol::LIST as u8, tail)), Response::Done);
+    assert_eq!(request(&mut state, write(20, tail)), rejected);
+    assert_eq!(request(&mut state, Request::Info), info(2, 0, 1));
+    let read = |offset| Request::ReadList { list: List::Macro, index: 0, offset };
+    let list = |actions: &[_]| Response::List { length: 13, actions: actions.to_vec() };
+    assert_eq!(request(&mut state, read(0)), list(head));
+    assert_eq!(request(&mut state, read(protocol::LIST as u8)), list(tail));
+    let read = Request::ReadList { list: List::Chord, index: 0, offset: 0 };
+    assert_eq!(request(&mut state, read), rejected);
+    assert_eq!(state.keymap().macro_(0)[12], Action::Tap(40));
+}
+
+#[test]
+fn cycle() {
+    use protocol::Action::*;
+    let mut state = State::new(crate::test_config());
+    state.set_keymap(crate::Keymap::builder().build());
+    let write = |list, actions: &[_]| {
+        let actions = actions.to_vec();
+        Request::WriteList { list, index: 0, offset: 0, actions }
+    };
+    assert_eq!(request(&mut state, write(List::Macro, &[])), Response::Done);
+    assert_eq!(request(&mut state, write(List::Chord, &[Macro(0)])), Response::Done);
+    let rejected = Response::Error(Error::Rejected);
+    assert_eq!(request(&mut state, write(List::Macro, &[Chord(0)])), rejected);
+    assert!(state.keymap().macro_(0).is_empty());
+}
+
+#[test]
+fn errors() {
+    let mut state = State::new(crate::test_config());
+    let mut response = |report: &[u8]| Response::decode(&handle(&mut state, report));
+    let mut report = Request::Info.encode();
+    report[0] = protocol::VERSION + 1;
+    assert_eq!(response(&report), Ok(Response::Error(Error::Version)));
+    report[0] = protocol::VERSION;
+    report[1] = 0x42;
+    assert_eq!(response(&report), Ok(Response::Error(Error::Malformed)));
+    assert_eq!(response(&[protocol::VERSION]), Ok(Response::Error(Error::Malformed)));
+}
@@ -0,0 +1,28 @@
+// Copyright 2021-2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! USB identification shared by the build script and the host tool (see `cargo xtask config`).
+
+/// Environment variables overwriting the vendor and product IDs (in hexadecimal) and their
+/// defaults (the pid.codes test IDs, only meant for development).
+pub const IDS: [(&str, &str); 2] = [("ONEKIBU_USB_VID", "1209"), ("ONEKIBU_USB_PID", "0001")];
+
+/// Returns the vendor and product IDs.
+pub fn ids() -> [u16; 2] {
+    IDS.map(|(name, default)| {
+        let id = std::env::var(name).unwrap_or_else(|_| default.to_string());
+        u16::from_str_radix(id.trim_start_matches("0x"), 16)
+            .unwrap_or_else(|_| panic!("{name} must be a 16-bit hexadecimal number."))
+    })
+}
@@ -0,0 +1,298 @@
+// Copyright 2021-2022 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Host side of the vendor HID configuration channel (Linux only).
+
+use crate::protocol::{Action, List, Repeat, Request, Response, Timings, LIST, REPORT};
+use std::fs::File;
+use std::io::{Read, Write};
+
+/// Start of the report descriptor of the configuration channel.
+const DESCRIPTOR: &[u8] = &[0x06, 0x00, 0xff, 0x09, 0x01];
+
+/// Maximum number of actions in a chord or macro.
+const MAX_LIST: usize = 255;
+
+pub struct Device {
+    file: File,
+}
+
+impl Device {
+    /// Opens the configuration channel of the plugged firmware (with this serial number, if any).
+    pub fn open(serial: Option<&str>) -> Device {
+        let hid_id = format!("HID_ID=0003:{}", vid_pid());
+        let mut found = Vec::new();
+        for entry in std::fs::read_dir("/sys/class/hidraw").unwrap() {
+            let path = entry.unwrap().path();
+            let uevent = std::fs::read_to_string(path.join("device/uevent")).unwrap();
+            let descriptor = std::fs::read(path.join("device/report_descriptor")).unwrap();
+            let uniq = uevent.lines().find_map(|x| x.strip_prefix("HID_UNIQ=")).unwrap_or("");
+            if !uevent.lines().any(|x| x == hid_id)
+                || !descriptor.starts_with(DESCRIPTOR)
+                || serial.is_some_and(|x| x != uniq)
+            {
+                continue;
+            }
+            found.push((path.file_name().unwrap().to_owned(), uniq.to_string()));
+        }
+        let name = match &found[..] {
+            [] => panic!("No plugged firmware with a configuration channel."),
+            [(name, _)] => name,
+            _ => {
+                let serials: Vec<_> = found.iter().map(|(_, x)| x).collect();
+                panic!("Several plugged firmwares, select one with --serial among {serials:?}.");
+            }
+        };
+        let node = std::path::Path::new("/dev").join(name);
+        let file = File::options().read(true).write(true).open(&node);
+        let file = file.unwrap_or_else(|e| panic!("Failed to open {node:?}: {e}"));
+        Device { file }
+    }
+
+    fn request(&mut self, request: Request) -> Response {
+        // The first byte is the report ID (none).
+        let mut report = vec![0];
+        report.extend_from_slice(&request.encode());
+        self.file.write_all(&report).unwrap();
+        let mut report = [0; REPORT];
+        self.file.read_exact(&mut report).unwrap();
+        match Response::decode(&report) {
+            Ok(Response::Error(error)) => panic!("{request:?} failed: {error:?}"),
+            Ok(response) => response,
+            Err(error) => panic!("Invalid response to {request:?}: {error:?}"),
+        }
+    }
+
+    fn timings(&mut self) -> Timings {
+        match self.request(Request::ReadTimings) {
+            Response::Timings(timings) => timings,
+            response => panic!("Unexpected response {response:?}"),
+        }
+    }
+
+    /// Reads all the actions of a chord or macro.
+    fn list(&mut self, list: List, index: u8) -> Vec<Action> {
+        let mut result = Vec::new();
+        loop {
+            let offset = result.len() as u8;
+            match self.request(Request::ReadList { list, index, offset }) {
+                Response::List { length, actions } => {
+                    result.extend(actions);
+                    if result.len() >= length as usize {
+                        return result;
+                    }
+                }
+                response => panic!("Unexpected response {response:?}"),
+            }
+        }
+    }
+}
+
+/// Shows the timings, keymap, chords, and macros.
+pub fn show(device: &mut Device) {
+    let timings = device.timings();
+    println!("long {}", timings.long);
+    println!("cancel {}", timings.cancel);
+    println!("letter {}", timings.letter);
+    match timings.word {
+        Some(word) => println!("word {word}"),
+        None => println!("word off"),
+    }
+    match timings.repeat {
+        Some(Repeat { window, delay, interval }) => println!("repeat {window} {delay} {interval}"),
+        None => println!("repeat off"),
+    }
+    let (layers, chords, macros) = match device.request(Request::Info) {
+        Response::Info { layers, chords, macros } => (layers, chords, macros),
+        response => panic!("Unexpected response {response:?}"),
+    };
+    for layer in 0 .. layers {
+        println!("layer {layer}:");
+        for sequence in 1 ..= 254 {
+            if let Response::Action(Some(action)) =
+                device.request(Request::ReadAction { layer, sequence })
+            {
+                println!("  {} {action:?}", format_sequence(sequence));
+            }
+        }
+    }
+    if let Response::Action(Some(action)) =
+        device.request(Request::ReadAction { layer: 0, sequence: 0 })
+    {
+        println!("word: {action:?}");
+    }
+    for chord in 0 .. chords {
+        println!("chord {chord}: {:?}", device.list(List::Chord, chord));
+    }
+    for macro_ in 0 .. macros {
+        println!("macro {macro_}: {:?}", device.list(List::Macro, macro_));
+    }
+}
+
+/// Sets a duration in milliseconds (0 disables the word gap).
+pub fn set(device: &mut Device, name: &str, millis: u32) {
+    let mut timings = device.timings();
+    match name {
+        "long" => timings.long = millis,
+        "cancel" => timings.cancel = millis,
+        "letter" => timings.letter = millis,
+        "word" => timings.word = Some(millis).filter(|&x| x != 0),
+        _ => panic!("Unknown duration {name:?} (expected long, cancel, letter, or word)."),
+    }
+    device.request(Request::WriteTimings(timings));
+}
+
+/// Sets the repeat durations (window, delay, and interval) in milliseconds or disables them.
+pub fn repeat(device: &mut Device, millis: &[u32]) {
+    let mut timings = device.timings();
+    timings.repeat = match *millis {
+        [] => None,
+        [window, delay, interval] => Some(Repeat { window, delay, interval }),
+        _ => panic!("Expected the window, delay, and interval durations (or none to disable)."),
+    };
+    device.request(Request::WriteTimings(timings));
+}
+
+/// Maps a sequence to an action in a layer (or unmaps it).
+///
+/// The empty sequence stands for the word action.
+pub fn map(device: &mut Device, layer: u8, sequence: &str, action: Option<&str>) {
+    let sequence = parse_sequence(sequence);
+    let action = action.map(parse_action);
+    device.request(Request::WriteAction { layer, sequence, action });
+}
+
+/// Sets the actions of a chord or macro (creating it if it is the next one).
+pub fn list(device: &mut Device, list: List, index: u8, actions: &[String]) {
+    let actions: Vec<_> = actions.iter().map(|x| parse_action(x)).collect();
+    assert!(actions.len() <= MAX_LIST, "Too many actions.");
+    let mut offset = 0;
+    // Empty lists are also written.
+    for chunk in actions.chunks(LIST).chain(actions.is_empty().then_some(&[][..])) {
+        let actions = chunk.to_vec();
+        device.request(Request::WriteList { list, index, offset, actions });
+        offset += chunk.len() as u8;
+    }
+}
+
+/// Returns the vendor and product IDs of the firmware (as in the kernel `HID_ID`).
+///
+/// As when building the firmware, the `ONEKIBU_USB_VID` and `ONEKIBU_USB_PID` environment
+/// variables overwrite the defaults.
+fn vid_pid() -> String {
+    let [vid, pid] = crate::usb_id::ids();
+    format!("{vid:08X}:{pid:08X}")
+}
+
+/// Parses a sequence of `.` and `-` into its index (see `keymap::parse()` in the firmware).
+fn parse_sequence(sequence: &str) -> u8 {
+    assert!(sequence.len() <= 7, "Sequences have at most 7 bits.");
+    sequence.chars().fold(0, |r, x| match x {
+        '.' => 2 * r + 1,
+        '-' => 2 * r + 2,
+        _ => panic!("Invalid sequence {sequence:?} (expected dots and dashes)."),
+    })
+}
+
+fn format_sequence(mut sequence: u8) -> String {
+    let mut result = Vec::new();
+    while sequence > 0 {
+        let dot = sequence % 2 == 1;
+        result.push(if dot { '.' } else { '-' });
+        sequence = (sequence - if dot { 1 } else { 2 }) / 2;
+    }
+    result.iter().rev().collect()
+}
+
+/// Parses an action as shown (e.g. `Tap(4)`, `Move(-1, 0)`, `Unicode('é')`, or `Cancel`).
+fn parse_action(action: &str) -> Action {
+    try_parse_action(action.trim()).unwrap_or_else(|| panic!("Invalid action {action:?}."))
+}
+
+fn try_parse_action(action: &str) -> Option<Action> {
+    let (name, args) = match action.split_once('(') {
+        Some((name, args)) => (name, args.strip_suffix(')')?),
+        None => (action, ""),
+    };
+    let args: Vec<&str> = args.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()).collect();
+    let byte = || match args[..] {
+        [x] => x.parse::<u8>().ok(),
+        _ => None,
+    };
+    let pair = || match args[..] {
+        [x, y] => Some((x.parse::<i8>().ok()?, y.parse::<i8>().ok()?)),
+        _ => None,
+    };
+    let none = || args.is_empty().then_some(());
+    Some(match name {
+        "Tap" => Action::Tap(byte()?),
+        "Press" => Action::Press(byte()?),
+        "Release" => Action::Release(byte()?),
+        "Toggle" => Action::Toggle(byte()?),
+        "Consumer" => match args[..] {
+            [x] => Action::Consumer(x.parse().ok()?),
+            _ => return None,
+        },
+        "Move" => pair().map(|(x, y)| Action::Move(x, y))?,
+        "Scroll" => pair().map(|(x, y)| Action::Scroll(x, y))?,
+        "Click" => Action::Click(byte()?),
+        "Drag" => Action::Drag(byte()?),
+        "PrepareSequence" => none().map(|_| Action::PrepareSequence)?,
+        "CommitSequence" => none().map(|_| Action::CommitSequence)?,
+        "Chord" => Action::Chord(byte()?),
+        "Macro" => Action::Macro(byte()?),
+        "Unicode" => match args[..] {
+            [x] => {
+                let mut chars = x.trim_matches('\'').chars();
+                match (chars.next(), chars.next()) {
+                    (Some(x), None) => Action::Unicode(x),
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        },
+        "NextUnicodeMethod" => none().map(|_| Action::NextUnicodeMethod)?,
+        "Layer" => Action::Layer(byte()?),
+        "ToggleLayer" => Action::ToggleLayer(byte()?),
+        "Cancel" => none().map(|_| Action::Cancel)?,
+        _ => return None,
+    })
+}
+
+#[test]
+fn sequences() {
+    for (text, sequence) in [("", 0), (".", 1), ("-", 2), (".-", 4), ("-------", 254)] {
+        assert_eq!(parse_sequence(text), sequence);
+        assert_eq!(format_sequence(sequence), text);
+    }
+}
+
+#[test]
+fn actions() {
+    // Actions are parsed as shown.
+    for action in [
+        Action::Tap(4),
+        Action::Consumer(233),
+        Action::Move(-1, 10),
+        Action::Unicode('é'),
+        Action::NextUnicodeMethod,
+        Action::ToggleLayer(1),
+    ] {
+        assert_eq!(try_parse_action(&format!("{action:?}")), Some(action));
+    }
+    assert_eq!(try_parse_action("Unicode(x)"), Some(Action::Unicode('x')));
+    assert_eq!(try_parse_action("Tap(256)"), None);
+    assert_eq!(try_parse_action("Cancel(1)"), None);
+    assert_eq!(try_parse_action("Move(1)"), None);
+}
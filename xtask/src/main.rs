@@ -12,6 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+extern crate alloc;
+
 use rustc_demangle::demangle;
 use std::cmp::Reverse;
 use std::collections::BinaryHeap;
@@ -20,6 +22,14 @@ use std::os::unix::process::CommandExt;
 use std::path::Path;
 use structopt::StructOpt;
 
+mod config;
+// The host only uses half of the protocol.
+#[allow(dead_code)]
+#[path = "../../firmware/src/protocol.rs"]
+mod protocol;
+#[path = "../../firmware/usb_id.rs"]
+mod usb_id;
+
 #[derive(Debug, StructOpt)]
 enum Flags {
     /// Builds the firmware
@@ -28,6 +38,9 @@ enum Flags {
     /// Starts a gdb session
     Gdb(Gdb),
 
+    /// Reads or writes the configuration of the plugged firmware
+    Config(Config),
+
     /// Runs rustfmt
     Fmt,
 
@@ -52,6 +65,14 @@ struct Build {
     #[structopt(long)]
     log: Option<String>,
 
+    /// Log the proportion of time spent sleeping
+    #[structopt(long)]
+    sleep_stats: bool,
+
+    /// Build the firmware without the USB serial console
+    #[structopt(long)]
+    no_console: bool,
+
     /// Show the size of the firmware
     #[structopt(long)]
     size: bool,
@@ -78,6 +99,54 @@ enum Gdb {
     },
 }
 
+#[derive(Debug, StructOpt)]
+struct Config {
+    /// Select the firmware with this USB serial number (if several are plugged)
+    #[structopt(long)]
+    serial: Option<String>,
+
+    #[structopt(subcommand)]
+    command: ConfigCommand,
+}
+
+#[derive(Debug, StructOpt)]
+enum ConfigCommand {
+    /// Shows the durations, keymap, chords, and macros
+    Show,
+
+    /// Sets a duration (long, cancel, letter, or word) in milliseconds
+    Set {
+        name: String,
+
+        /// Duration in milliseconds (0 disables the word gap)
+        millis: u32,
+    },
+
+    /// Sets the repeat durations in milliseconds or disables the repeat gesture
+    Repeat {
+        /// Window, delay, and interval (none disables the repeat gesture)
+        millis: Vec<u32>,
+    },
+
+    /// Maps a sequence (e.g. .-) to an action (e.g. "Tap(4)") or unmaps it
+    Map {
+        /// Layer of the sequence
+        #[structopt(long, default_value = "0")]
+        layer: u8,
+
+        /// Sequence of dots and dashes (the empty sequence is the word action)
+        sequence: String,
+
+        action: Option<String>,
+    },
+
+    /// Sets the actions of a chord (creating it if it is the next one)
+    Chord { index: u8, actions: Vec<String> },
+
+    /// Sets the actions of a macro (creating it if it is the next one)
+    Macro { index: u8, actions: Vec<String> },
+}
+
 const BOARDS: &[&str] = &["nrf52840-dk", "nrf52840-dongle", "nrf52840-mdk-dongle", "solo"];
 const TARGET: &str = "thumbv7em-none-eabi";
 
@@ -86,6 +155,7 @@ impl Flags {
         match self {
             Flags::Build(x) => x.execute(),
             Flags::Gdb(x) => x.execute(),
+            Flags::Config(x) => x.execute(),
             Flags::Fmt => {
                 for dir in ["xtask", "firmware"] {
                     let mut cargo = Command::new("cargo");
@@ -113,6 +183,10 @@ impl Flags {
                 }
             }
             Flags::Test => {
+                let mut cargo = Command::new("cargo");
+                cargo.dir("xtask");
+                cargo.arg("test");
+                cargo.spawn();
                 let mut cargo = Command::new("cargo");
                 cargo.dir("firmware");
                 cargo.arg("test");
@@ -136,6 +210,9 @@ impl Build {
         cargo.arg("build");
         cargo.arg(format!("--target={TARGET}"));
         cargo.arg(format!("--features=board-{}", self.board));
+        if self.no_console {
+            cargo.arg("--no-default-features");
+        }
         if self.release {
             cargo.arg("--release");
             rustflags.push("-C codegen-units=1");
@@ -153,6 +230,10 @@ impl Build {
             rustflags.push("-C link-arg=-Tdefmt.x");
             cargo.arg("--features=log");
         }
+        if self.sleep_stats {
+            assert!(log != "off", "--sleep-stats needs logging");
+            cargo.arg("--features=sleep-stats");
+        }
         cargo.env("RUSTFLAGS", rustflags.join(" "));
         cargo.spawn();
         let elf = elf(self.release);
@@ -241,6 +322,26 @@ impl Gdb {
     }
 }
 
+impl Config {
+    fn execute(self) {
+        let device = &mut config::Device::open(self.serial.as_deref());
+        match self.command {
+            ConfigCommand::Show => config::show(device),
+            ConfigCommand::Set { name, millis } => config::set(device, &name, millis),
+            ConfigCommand::Repeat { millis } => config::repeat(device, &millis),
+            ConfigCommand::Map { layer, sequence, action } => {
+                config::map(device, layer, &sequence, action.as_deref())
+            }
+            ConfigCommand::Chord { index, actions } => {
+                config::list(device, protocol::List::Chord, index, &actions)
+            }
+            ConfigCommand::Macro { index, actions } => {
+                config::list(device, protocol::List::Macro, index, &actions)
+            }
+        }
+    }
+}
+
 struct Command {
     command: std::process::Command,
 }